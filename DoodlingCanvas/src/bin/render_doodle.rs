@@ -0,0 +1,139 @@
+#![allow(non_snake_case)]
+// Renders a stroke document to an image without opening a window, e.g. to regenerate thumbnails
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context};
+use image::ImageFormat;
+use winit::dpi::PhysicalSize;
+use DoodlingCanvas::{
+    document::StrokeDocument,
+    export::{self, ExportFormat, ExportOptions},
+    layers::Background,
+    render_state::State,
+    utils,
+};
+
+const USAGE: &str = "Usage: render_doodle <document.json> <output.png|jpg|webp> [--scale <factor>] [--quality <1-100>] [--cpu]
+The output format is picked from the file extension, --quality only applies to JPEG (default 90).
+The GPU is used when there is one, --cpu always renders on the CPU.";
+
+struct Args {
+    input: PathBuf,
+    output: PathBuf,
+    scale: f32,
+    quality: u8,
+    cpu: bool,
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> anyhow::Result<Args> {
+    let mut paths = Vec::new();
+    let mut scale: f32 = 1.0;
+    let mut quality = 90;
+    let mut cpu = false;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--scale" => {
+                let value = args.next().context("--scale needs a value")?;
+                scale = value.parse().context("Invalid scale")?;
+                if !scale.is_finite() || scale <= 0.0 {
+                    bail!("The scale must be positive");
+                }
+            }
+            "--quality" => {
+                let value = args.next().context("--quality needs a value")?;
+                quality = value.parse().context("Invalid quality")?;
+                if !(1..=100).contains(&quality) {
+                    bail!("The quality must be between 1 and 100");
+                }
+            }
+            "--cpu" => cpu = true,
+            _ if arg.starts_with("--") => bail!("Unknown option {}", arg),
+            _ => paths.push(PathBuf::from(arg)),
+        }
+    }
+    let [input, output] = <[PathBuf; 2]>::try_from(paths)
+        .map_err(|_| anyhow::anyhow!("Expected an input and an output path"))?;
+    Ok(Args {
+        input,
+        output,
+        scale,
+        quality,
+        cpu,
+    })
+}
+
+fn save(
+    image: image::RgbaImage,
+    background: Background,
+    path: &Path,
+    quality: u8,
+) -> anyhow::Result<()> {
+    let format = match ImageFormat::from_path(path).context("Unknown output format")? {
+        ImageFormat::Png => ExportFormat::Png,
+        ImageFormat::Jpeg => ExportFormat::Jpeg,
+        ImageFormat::WebP => ExportFormat::WebP,
+        format => bail!("Unsupported output format {:?}", format),
+    };
+    // The document is already rendered at its scale
+    let options = ExportOptions {
+        format,
+        quality,
+        ..ExportOptions::default()
+    };
+    std::fs::write(path, export::encode(image, background, &options)?)?;
+    Ok(())
+}
+
+async fn render(args: &Args) -> anyhow::Result<()> {
+    let json = std::fs::read_to_string(&args.input)
+        .with_context(|| format!("Failed to read {}", args.input.display()))?;
+    let document = StrokeDocument::from_json(&json)?.scaled(args.scale);
+    // Checked before setting up a GPU, the strokes of these aren't the whole doodle
+    document.ensure_complete()?;
+    // Same limit as the exports of the widget, larger images don't fit in a texture
+    let max_size = (utils::MAX_CANVAS_SIZE as f32 * export::MAX_SCALE) as u32;
+    if document.width > max_size || document.height > max_size {
+        bail!(
+            "The scaled doodle is {}x{}, it can't be larger than {}x{}",
+            document.width,
+            document.height,
+            max_size,
+            max_size
+        );
+    }
+    let gpu = if args.cpu {
+        None
+    } else {
+        match State::new_headless(PhysicalSize::new(document.width, document.height)).await {
+            Ok(state) => Some(state),
+            Err(err) => {
+                log::warn!("Rendering on the CPU: {:#}", err);
+                None
+            }
+        }
+    };
+    let image = match gpu {
+        Some(mut state) => {
+            document.draw(&mut state)?;
+            state.extract_framebuffer().await?
+        }
+        None => document.rasterize()?,
+    };
+    save(image, document.background, &args.output, args.quality)
+        .with_context(|| format!("Failed to write {}", args.output.display()))
+}
+
+fn main() {
+    env_logger::init();
+    let args = match parse_args(std::env::args().skip(1)) {
+        Ok(args) => args,
+        Err(err) => {
+            eprintln!("{}\n{}", err, USAGE);
+            std::process::exit(2);
+        }
+    };
+    if let Err(err) = pollster::block_on(render(&args)) {
+        eprintln!("{:#}", err);
+        std::process::exit(1);
+    }
+}
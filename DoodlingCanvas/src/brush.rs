@@ -1,35 +1,155 @@
-use crate::{render_state::{State, Vertex, RenderCommands}, utils};
-
- //TODO: Use unforms instead calculating vertices here
- pub struct Rectangle{
-    vertices: [Vertex;6],
-    buffer: wgpu::Buffer,
- }
-
- impl Rectangle{
-    pub fn new(render_state: &mut State, dimensions : [f32;4]) -> Self
-    {
-        let rect: [Vertex; 6] = Self::dimensions_to_vertices(dimensions);
-        let inner_buff = render_state.make_test_buffer(rect.as_slice());
-        Self { vertices: rect, buffer: inner_buff }
+use wgpu::util::DeviceExt;
+
+use crate::{color, render_state::Vertex, stroke::Dab};
+
+// Corners of the unit square every dab is stamped with, as two triangles
+const QUAD: [Vertex; 6] = [
+    Vertex {
+        position: [-0.5, -0.5],
+    },
+    Vertex {
+        position: [0.5, -0.5],
+    },
+    Vertex {
+        position: [0.5, 0.5],
+    },
+    Vertex {
+        position: [0.5, 0.5],
+    },
+    Vertex {
+        position: [-0.5, 0.5],
+    },
+    Vertex {
+        position: [-0.5, -0.5],
+    },
+];
+
+/// Per instance data of the brush pipeline, see `shaders/canvas_shader.wgsl`
+#[repr(C)]
+#[derive(Copy, Clone, Debug, PartialEq, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct DabInstance {
+    // Center in canvas pixels
+    pub position: [f32; 2],
+    pub size: f32,
+    // Radians, clockwise on the canvas
+    pub rotation: f32,
+    // Linear color with straight alpha
+    pub color: [f32; 4],
+}
+
+impl DabInstance {
+    // Location 0 is the corner of the quad
+    const ATTRIBS: [wgpu::VertexAttribute; 4] =
+        wgpu::vertex_attr_array![1 => Float32x2, 2 => Float32, 3 => Float32, 4 => Float32x4];
+
+    pub fn desc() -> wgpu::VertexBufferLayout<'static> {
+        wgpu::VertexBufferLayout {
+            array_stride: std::mem::size_of::<Self>() as wgpu::BufferAddress,
+            step_mode: wgpu::VertexStepMode::Instance,
+            attributes: &Self::ATTRIBS,
+        }
     }
-    fn dimensions_to_vertices(dimensions : [f32;4]) -> [Vertex;6]
-    {
-        let win = (utils::WINDOW_WIDTH as f32 / 2.0,utils::WINDOW_HEIGHT as f32 / 2.0);
-        let dimensions = [dimensions[0] , -dimensions[1], dimensions[2], dimensions[3]];
-        [
-            Vertex{position: [dimensions[0]/win.0 - 1.0,dimensions[1]/win.1 + 1.0]},
-            Vertex{position: [dimensions[0]/win.0 - 1.0,(dimensions[1]-dimensions[3])/win.1 + 1.0]},
-            Vertex{position: [(dimensions[0]+dimensions[2])/win.0 - 1.0,(dimensions[1]-dimensions[3])/win.1 + 1.0]},
-            Vertex{position: [(dimensions[0]+dimensions[2])/win.0 - 1.0,(dimensions[1]-dimensions[3])/win.1  + 1.0]},
-            Vertex{position: [(dimensions[0]+dimensions[2])/win.0 - 1.0,dimensions[1]/win.1 + 1.0]},
-            Vertex{position: [dimensions[0]/win.0 - 1.0,dimensions[1]/win.1 + 1.0]},
-        ]
+}
+
+impl From<&Dab> for DabInstance {
+    fn from(dab: &Dab) -> Self {
+        Self {
+            position: dab.position,
+            size: dab.size,
+            rotation: dab.rotation,
+            color: color::to_linear(dab.color),
+        }
     }
-    // Prepares the rectangle for drawing adnd registers the drawing command to the render state
-    pub fn draw_to(&mut self, render_state : &mut State,commands: &mut RenderCommands, offset : [f32;2])
-    {
-        let adjust_offset = [2.0 * offset[0] / (utils::WINDOW_WIDTH as f32 ) ,2.0 * offset[1] / (utils::WINDOW_HEIGHT as f32 ) ];
-        render_state.draw_buffer(commands, &self.buffer,adjust_offset);
+}
+
+/// Dabs waiting to be stamped into a layer. They are drawn together with a single instanced draw call
+/// when the frame is rendered, or earlier when something needs the layers to be up to date.
+pub struct DabBatch {
+    quad: wgpu::Buffer,
+    instances: wgpu::Buffer,
+    // Number of dabs the instance buffer can hold
+    capacity: usize,
+    layer: usize,
+    pending: Vec<DabInstance>,
+}
+
+impl DabBatch {
+    const INITIAL_CAPACITY: usize = 256;
+
+    pub fn new(device: &wgpu::Device) -> Self {
+        let quad = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Brush Quad Buffer"),
+            contents: bytemuck::cast_slice(&QUAD),
+            usage: wgpu::BufferUsages::VERTEX,
+        });
+        Self {
+            quad,
+            instances: Self::create_instance_buffer(device, Self::INITIAL_CAPACITY),
+            capacity: Self::INITIAL_CAPACITY,
+            layer: 0,
+            pending: Vec::new(),
+        }
+    }
+
+    fn create_instance_buffer(device: &wgpu::Device, capacity: usize) -> wgpu::Buffer {
+        device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Dab Instance Buffer"),
+            size: (capacity * std::mem::size_of::<DabInstance>()) as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        })
+    }
+
+    // The layer the pending dabs go into, None when there are none
+    pub fn layer(&self) -> Option<usize> {
+        (!self.pending.is_empty()).then_some(self.layer)
+    }
+
+    // The caller draws the batch first when the dab goes into another layer
+    pub fn push(&mut self, layer: usize, dab: &Dab) {
+        debug_assert!(self.layer().map_or(true, |pending| pending == layer));
+        self.layer = layer;
+        self.pending.push(DabInstance::from(dab));
+    }
+
+    // Records the pending dabs into `target` and empties the batch
+    pub fn draw(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        commands: &mut wgpu::CommandEncoder,
+        pipeline: &wgpu::RenderPipeline,
+        bind_group: &wgpu::BindGroup,
+        target: &wgpu::TextureView,
+    ) {
+        if self.pending.is_empty() {
+            return;
+        }
+        if self.pending.len() > self.capacity {
+            self.capacity = self.pending.len().next_power_of_two();
+            self.instances = Self::create_instance_buffer(device, self.capacity);
+        }
+        queue.write_buffer(&self.instances, 0, bytemuck::cast_slice(&self.pending));
+        let mut render_pass = commands.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("Brush Dabs Render Pass"),
+            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                view: target,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Load,
+                    store: wgpu::StoreOp::Store,
+                },
+            })],
+            depth_stencil_attachment: None,
+            timestamp_writes: None,
+            occlusion_query_set: None,
+        });
+        render_pass.set_pipeline(pipeline);
+        render_pass.set_bind_group(0, bind_group, &[]);
+        render_pass.set_vertex_buffer(0, self.quad.slice(..));
+        render_pass.set_vertex_buffer(1, self.instances.slice(..));
+        render_pass.draw(0..QUAD.len() as u32, 0..self.pending.len() as u32);
+        drop(render_pass);
+        self.pending.clear();
     }
- }
\ No newline at end of file
+}
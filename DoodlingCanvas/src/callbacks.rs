@@ -0,0 +1,60 @@
+use std::sync::{Arc, Mutex};
+
+use crate::tools::Tool;
+
+// Set from the page through `WindowHandler`, called from the event loop
+pub type Callback<T> = Arc<Mutex<Option<Box<dyn Fn(T)>>>>;
+
+/// Everything the page embedding the canvas can be told about. Clones share the same callbacks
+#[derive(Clone, Default)]
+pub struct Callbacks {
+    // sRGB with straight alpha
+    pub color_picked: Callback<image::Rgba<u8>>,
+    // A brush stroke was started and finished, shapes and fills are single edits
+    pub stroke_started: Callback<()>,
+    pub stroke_ended: Callback<()>,
+    // After every finished edit: strokes, shapes, fills, undo and layer changes
+    pub canvas_changed: Callback<()>,
+    pub tool_changed: Callback<Tool>,
+    // Something went wrong that the user should know about, e.g. the renderer couldn't be set up
+    pub error: Callback<String>,
+}
+
+impl Callbacks {
+    pub fn report_error(&self, message: String) {
+        log::error!("{}", message);
+        call(&self.error, message);
+    }
+}
+
+// Does nothing when no callback is set
+pub fn call<T>(callback: &Callback<T>, value: T) {
+    if let Some(callback) = &*callback.lock().unwrap() {
+        callback(value);
+    }
+}
+
+pub fn set<T>(callback: &Callback<T>, handler: impl Fn(T) + 'static) {
+    callback.lock().unwrap().replace(Box::new(handler));
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::Cell, rc::Rc};
+
+    use super::*;
+
+    #[test]
+    fn clones_share_their_callbacks() {
+        let callbacks = Callbacks::default();
+        // Nothing is set yet
+        call(&callbacks.tool_changed, Tool::Fill);
+        let seen = Rc::new(Cell::new(None));
+        let seen_by_handler = seen.clone();
+        set(&callbacks.clone().tool_changed, move |tool| {
+            seen_by_handler.set(Some(tool))
+        });
+        call(&callbacks.tool_changed, Tool::Eyedropper);
+        assert_eq!(seen.get(), Some(Tool::Eyedropper));
+    }
+}
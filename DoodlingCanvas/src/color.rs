@@ -0,0 +1,144 @@
+// The color pipeline, from the page to the exported file:
+// - Colors are handed around as 8-bit sRGB with straight alpha: brush colors, the color picker, imported images.
+// - The shaders get them as linear floats (`to_linear`) and blend premultiplied colors in linear space.
+// - Layers are `Rgba8UnormSrgb` textures: the GPU decodes them to linear when sampling and encodes
+//   to sRGB when writing, so they hold premultiplied linear colors stored as sRGB (`to_layer_pixel`).
+// - The surface is an sRGB format or viewed as one, see `State::choose_surface_format`.
+// - Exports are read back in RGBA order, turned back into straight sRGB (`from_layer_pixel`)
+//   and saved as PNGs tagged sRGB (`encode_png`).
+// Opaque colors go through all of it unchanged.
+// One sRGB channel to linear
+pub fn srgb_to_linear(value: u8) -> f32 {
+    let value = value as f32 / 255.0;
+    if value <= 0.04045 {
+        value / 12.92
+    } else {
+        ((value + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+// Linear color with straight alpha, as written to the shader uniforms
+pub fn to_linear(color: image::Rgba<u8>) -> [f32; 4] {
+    let [r, g, b, a] = color.0;
+    [
+        srgb_to_linear(r),
+        srgb_to_linear(g),
+        srgb_to_linear(b),
+        a as f32 / 255.0,
+    ]
+}
+
+pub fn linear_to_srgb(value: f32) -> u8 {
+    let value = value.clamp(0.0, 1.0);
+    let value = if value <= 0.0031308 {
+        value * 12.92
+    } else {
+        1.055 * value.powf(1.0 / 2.4) - 0.055
+    };
+    (value * 255.0).round() as u8
+}
+
+// The color as stored in the layer textures: premultiplied in linear space, then sRGB encoded
+pub fn to_layer_pixel(color: image::Rgba<u8>) -> image::Rgba<u8> {
+    let [r, g, b, a] = to_linear(color);
+    image::Rgba([
+        linear_to_srgb(r * a),
+        linear_to_srgb(g * a),
+        linear_to_srgb(b * a),
+        color.0[3],
+    ])
+}
+
+// Inverse of `to_layer_pixel`, fully transparent pixels have no color and come back as transparent black
+pub fn from_layer_pixel(pixel: image::Rgba<u8>) -> image::Rgba<u8> {
+    let [r, g, b, a] = pixel.0;
+    if a == 0 {
+        return image::Rgba([0, 0, 0, 0]);
+    }
+    let alpha = a as f32 / 255.0;
+    image::Rgba([
+        linear_to_srgb(srgb_to_linear(r) / alpha),
+        linear_to_srgb(srgb_to_linear(g) / alpha),
+        linear_to_srgb(srgb_to_linear(b) / alpha),
+        a,
+    ])
+}
+
+// CSS style "#rrggbbaa"
+pub fn to_hex(color: image::Rgba<u8>) -> String {
+    let [r, g, b, a] = color.0;
+    format!("#{:02x}{:02x}{:02x}{:02x}", r, g, b, a)
+}
+
+// Parses "#rrggbb" or "#rrggbbaa", colors without alpha are opaque
+pub fn from_hex(hex: &str) -> Option<image::Rgba<u8>> {
+    let digits = hex.strip_prefix('#')?;
+    if !digits.is_ascii() || !matches!(digits.len(), 6 | 8) {
+        return None;
+    }
+    let channel = |index: usize| u8::from_str_radix(digits.get(index * 2..index * 2 + 2)?, 16).ok();
+    let alpha = if digits.len() == 8 { channel(3)? } else { 255 };
+    Some(image::Rgba([channel(0)?, channel(1)?, channel(2)?, alpha]))
+}
+
+// PNG with an sRGB chunk, so viewers don't have to guess how to show the colors
+pub fn encode_png(image: &image::RgbaImage) -> anyhow::Result<Vec<u8>> {
+    let mut buffer = Vec::new();
+    let mut encoder = png::Encoder::new(&mut buffer, image.width(), image.height());
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.set_source_srgb(png::SrgbRenderingIntent::Perceptual);
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(image.as_raw())?;
+    writer.finish()?;
+    Ok(buffer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_channel_value_survives_linear_and_back() {
+        for value in 0..=255 {
+            assert_eq!(linear_to_srgb(srgb_to_linear(value)), value);
+        }
+    }
+
+    #[test]
+    fn opaque_colors_are_stored_unchanged() {
+        for value in 0..=255 {
+            let color = image::Rgba([value, 255 - value, value / 2, 255]);
+            assert_eq!(to_layer_pixel(color), color);
+            assert_eq!(from_layer_pixel(color), color);
+        }
+    }
+
+    #[test]
+    fn translucent_colors_round_trip_through_layers() {
+        let color = image::Rgba([200, 100, 50, 128]);
+        let pixel = to_layer_pixel(color);
+        // Premultiplied in linear space, which is lighter than scaling the sRGB value
+        assert!(pixel.0[0] > 100 && pixel.0[0] < 200);
+        let back = from_layer_pixel(pixel);
+        assert_eq!(back.0[3], 128);
+        for channel in 0..3 {
+            assert!(back.0[channel].abs_diff(color.0[channel]) <= 1);
+        }
+    }
+
+    #[test]
+    fn png_is_tagged_srgb_and_lossless() {
+        let image = image::RgbaImage::from_fn(16, 4, |x, y| {
+            image::Rgba([x as u8 * 16, y as u8 * 64, 255 - x as u8, 128 + y as u8])
+        });
+        let bytes = encode_png(&image).unwrap();
+        let decoder = png::Decoder::new(bytes.as_slice());
+        let reader = decoder.read_info().unwrap();
+        assert_eq!(
+            reader.info().srgb,
+            Some(png::SrgbRenderingIntent::Perceptual)
+        );
+        assert_eq!(image::load_from_memory(&bytes).unwrap().to_rgba8(), image);
+    }
+}
@@ -0,0 +1,282 @@
+use image::{Rgba, RgbaImage};
+
+use crate::{
+    color::{from_layer_pixel, linear_to_srgb, srgb_to_linear, to_linear},
+    layers::{Background, BlendMode},
+    stroke::{Dab, Stroke},
+};
+
+/// Reference implementation of the brush pipeline on the CPU, for machines without a GPU and to check the GPU output.
+/// It follows what the GPU does step by step: layers hold premultiplied linear colors encoded as 8-bit sRGB
+/// (like `Layer::FORMAT`), dabs cover the pixels whose centers are inside their square (`brush.rs`),
+/// blending is premultiplied "over" in linear space (`canvas_shader.wgsl`) and compositing uses the
+/// blend states of `BlendMode`.
+pub struct CpuCanvas {
+    width: u32,
+    height: u32,
+    // Ordered bottom to top
+    layers: Vec<CpuLayer>,
+    background: Background,
+}
+
+struct CpuLayer {
+    pixels: RgbaImage,
+    opacity: f32,
+    visible: bool,
+    blend_mode: BlendMode,
+}
+
+// Channels of a stored pixel as linear floats
+fn decode(pixel: &Rgba<u8>) -> [f32; 4] {
+    let [r, g, b, a] = pixel.0;
+    [
+        srgb_to_linear(r),
+        srgb_to_linear(g),
+        srgb_to_linear(b),
+        a as f32 / 255.0,
+    ]
+}
+
+fn encode(color: [f32; 4]) -> Rgba<u8> {
+    let [r, g, b, a] = color;
+    Rgba([
+        linear_to_srgb(r),
+        linear_to_srgb(g),
+        linear_to_srgb(b),
+        (a.clamp(0.0, 1.0) * 255.0).round() as u8,
+    ])
+}
+
+// Premultiplied source over destination
+fn over(src: [f32; 4], dst: [f32; 4]) -> [f32; 4] {
+    let inverse_alpha = 1.0 - src[3];
+    [
+        src[0] + dst[0] * inverse_alpha,
+        src[1] + dst[1] * inverse_alpha,
+        src[2] + dst[2] * inverse_alpha,
+        src[3] + dst[3] * inverse_alpha,
+    ]
+}
+
+// Same equations as `BlendMode::blend_state`
+fn blend(mode: BlendMode, src: [f32; 4], dst: [f32; 4]) -> [f32; 4] {
+    let mut out = over(src, dst);
+    for channel in 0..3 {
+        out[channel] = match mode {
+            BlendMode::Normal => out[channel],
+            BlendMode::Multiply => src[channel] * dst[channel] + dst[channel] * (1.0 - src[3]),
+            BlendMode::Screen => src[channel] + dst[channel] * (1.0 - src[channel]),
+            BlendMode::Add => src[channel] + dst[channel],
+        };
+    }
+    out
+}
+
+// First and one past the last pixel whose center lies in start..end
+fn covered_pixels(start: f32, end: f32, limit: u32) -> std::ops::Range<u32> {
+    let first = (start - 0.5).ceil().max(0.0);
+    let last = (end - 0.5).ceil().min(limit as f32);
+    if last <= first {
+        return 0..0;
+    }
+    first as u32..last as u32
+}
+
+impl CpuCanvas {
+    // A canvas with a single transparent layer over the default background, like `State`
+    pub fn new(width: u32, height: u32) -> Self {
+        Self {
+            width,
+            height,
+            layers: vec![CpuLayer {
+                pixels: RgbaImage::new(width, height),
+                opacity: 1.0,
+                visible: true,
+                blend_mode: BlendMode::Normal,
+            }],
+            background: Background::default(),
+        }
+    }
+
+    pub fn set_background(&mut self, background: Background) {
+        self.background = background;
+    }
+
+    pub fn layer_count(&self) -> usize {
+        self.layers.len()
+    }
+
+    // New layers are transparent and go on top
+    pub fn add_layer(&mut self, opacity: f32, visible: bool, blend_mode: BlendMode) {
+        self.layers.push(CpuLayer {
+            pixels: RgbaImage::new(self.width, self.height),
+            opacity: opacity.clamp(0.0, 1.0),
+            visible,
+            blend_mode,
+        });
+    }
+
+    pub fn set_layer(&mut self, index: usize, opacity: f32, visible: bool, blend_mode: BlendMode) {
+        if let Some(layer) = self.layers.get_mut(index) {
+            layer.opacity = opacity.clamp(0.0, 1.0);
+            layer.visible = visible;
+            layer.blend_mode = blend_mode;
+        }
+    }
+
+    pub fn draw_dab(&mut self, layer: usize, dab: &Dab) {
+        let Some(layer) = self.layers.get_mut(layer) else {
+            return;
+        };
+        let [r, g, b, a] = to_linear(dab.color);
+        let color = [r * a, g * a, b * a, a];
+        let half = dab.size / 2.0;
+        let [x, y] = dab.position;
+        let (sin, cos) = dab.rotation.sin_cos();
+        // Half the size of the box around the rotated square
+        let extent = half * (cos.abs() + sin.abs());
+        for row in covered_pixels(y - extent, y + extent, self.height) {
+            for column in covered_pixels(x - extent, x + extent, self.width) {
+                // Pixel center in the dab's own, unrotated, frame
+                let (dx, dy) = (column as f32 + 0.5 - x, row as f32 + 0.5 - y);
+                let (u, v) = (dx * cos + dy * sin, dy * cos - dx * sin);
+                if !(-half..half).contains(&u) || !(-half..half).contains(&v) {
+                    continue;
+                }
+                let pixel = layer.pixels.get_pixel_mut(column, row);
+                *pixel = encode(over(color, decode(pixel)));
+            }
+        }
+    }
+
+    pub fn draw_stroke(&mut self, stroke: &Stroke) {
+        for dab in stroke.dabs() {
+            self.draw_dab(stroke.layer, &dab);
+        }
+    }
+
+    pub fn layer(&self, index: usize) -> &RgbaImage {
+        &self.layers[index].pixels
+    }
+
+    // Composites the visible layers over the background, like `State::flatten`
+    pub fn flatten(&self) -> RgbaImage {
+        let mut output =
+            RgbaImage::from_pixel(self.width, self.height, self.background.to_layer_pixel());
+        for layer in self.layers.iter().filter(|layer| layer.visible) {
+            for (out, pixel) in output.pixels_mut().zip(layer.pixels.pixels()) {
+                let src = decode(pixel).map(|channel| channel * layer.opacity);
+                *out = encode(blend(layer.blend_mode, src, decode(out)));
+            }
+        }
+        output
+    }
+
+    // The flattened canvas with straight alpha, like `State::export`
+    pub fn export(&self) -> RgbaImage {
+        let mut output = self.flatten();
+        for pixel in output.pixels_mut() {
+            *pixel = from_layer_pixel(*pixel);
+        }
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dab(x: f32, y: f32, size: f32, color: Rgba<u8>) -> Dab {
+        Dab {
+            position: [x, y],
+            size,
+            rotation: 0.0,
+            color,
+        }
+    }
+
+    #[test]
+    fn dab_covers_pixel_centers_inside_its_square() {
+        let mut canvas = CpuCanvas::new(16, 16);
+        canvas.add_layer(1.0, true, BlendMode::Normal);
+        canvas.draw_dab(1, &dab(8.0, 8.0, 4.0, Rgba([255, 0, 0, 255])));
+        let covered: Vec<(u32, u32)> = canvas
+            .layer(1)
+            .enumerate_pixels()
+            .filter(|(_, _, pixel)| pixel.0[3] > 0)
+            .map(|(x, y, _)| (x, y))
+            .collect();
+        assert_eq!(covered.len(), 16);
+        assert!(covered
+            .iter()
+            .all(|&(x, y)| (6..10).contains(&x) && (6..10).contains(&y)));
+    }
+
+    #[test]
+    fn rotated_dab_is_a_diamond() {
+        let mut canvas = CpuCanvas::new(16, 16);
+        canvas.add_layer(1.0, true, BlendMode::Normal);
+        let dab = Dab {
+            rotation: std::f32::consts::FRAC_PI_4,
+            ..dab(8.0, 8.0, 8.0, Rgba([255, 0, 0, 255]))
+        };
+        canvas.draw_dab(1, &dab);
+        let covered = |x, y| canvas.layer(1).get_pixel(x, y).0[3] > 0;
+        // The corners now point along the axes, past the edges of an unrotated dab
+        assert!(covered(3, 7) && covered(12, 8) && covered(8, 3) && covered(7, 12));
+        assert!(!covered(4, 4) && !covered(11, 11));
+    }
+
+    #[test]
+    fn dabs_are_clipped_to_the_canvas() {
+        let mut canvas = CpuCanvas::new(8, 8);
+        canvas.draw_dab(0, &dab(0.0, 7.5, 6.0, Rgba([0, 0, 0, 255])));
+        canvas.draw_dab(0, &dab(-20.0, -20.0, 6.0, Rgba([0, 0, 0, 255])));
+        let black = canvas
+            .layer(0)
+            .pixels()
+            .filter(|pixel| **pixel == Rgba([0, 0, 0, 255]))
+            .count();
+        assert_eq!(black, 3 * 4);
+    }
+
+    #[test]
+    fn opaque_dab_replaces_the_background() {
+        let mut canvas = CpuCanvas::new(4, 4);
+        canvas.draw_dab(0, &dab(2.0, 2.0, 4.0, Rgba([10, 200, 30, 255])));
+        assert!(canvas
+            .flatten()
+            .pixels()
+            .all(|pixel| *pixel == Rgba([10, 200, 30, 255])));
+    }
+
+    #[test]
+    fn translucent_dab_is_stored_premultiplied() {
+        let mut canvas = CpuCanvas::new(4, 4);
+        canvas.add_layer(1.0, true, BlendMode::Normal);
+        canvas.draw_dab(1, &dab(2.0, 2.0, 4.0, Rgba([255, 255, 255, 128])));
+        // Half of linear white is 188 once sRGB encoded
+        assert_eq!(*canvas.layer(1).get_pixel(0, 0), Rgba([188, 188, 188, 128]));
+    }
+
+    #[test]
+    fn hidden_layers_are_skipped() {
+        let mut canvas = CpuCanvas::new(4, 4);
+        canvas.add_layer(1.0, false, BlendMode::Normal);
+        canvas.draw_dab(1, &dab(2.0, 2.0, 4.0, Rgba([255, 0, 0, 255])));
+        assert_eq!(
+            *canvas.flatten().get_pixel(1, 1),
+            Rgba([124, 124, 124, 255])
+        );
+    }
+
+    #[test]
+    fn transparent_background_exports_straight_alpha() {
+        let mut canvas = CpuCanvas::new(4, 4);
+        canvas.set_background(Background::Transparent);
+        canvas.draw_dab(0, &dab(1.0, 1.0, 2.0, Rgba([255, 255, 255, 128])));
+        let exported = canvas.export();
+        assert_eq!(*exported.get_pixel(0, 0), Rgba([255, 255, 255, 128]));
+        assert_eq!(*exported.get_pixel(3, 3), Rgba([0, 0, 0, 0]));
+    }
+}
@@ -0,0 +1,325 @@
+use anyhow::{anyhow, bail, Context};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    color,
+    cpu_raster::CpuCanvas,
+    layers::{Background, BlendMode},
+    stroke::{PressureCurve, Stroke, StrokePoint},
+    utils,
+};
+#[cfg(feature = "render")]
+use crate::{layers::LayerCommand, render_state::State};
+
+/// A doodle described by its strokes instead of its pixels, so it can be rendered again at any scale.
+/// Stored as JSON:
+/// {
+///   "width": 800, "height": 600, "background": "#7c7c7cff",
+///   "layers": [{ "opacity": 1.0, "visible": true, "blend_mode": "Normal" }],
+///   "strokes": [{ "layer": 0, "color": "#ff0000ff", "size": 10.0, "pressure_curve": 1.0,
+///                 "points": [[x, y, pressure], ...] }]
+/// }
+/// Only brush strokes are recorded, "incomplete": true marks documents of canvases also changed otherwise.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct StrokeDocument {
+    pub width: u32,
+    pub height: u32,
+    // Bottom to top, the first one is the background layer
+    #[serde(default = "default_layers")]
+    pub layers: Vec<LayerSettings>,
+    #[serde(default)]
+    pub strokes: Vec<StrokeData>,
+    // Documents written before backgrounds existed get the old grey one
+    #[serde(default)]
+    pub background: Background,
+    // Set when fills, shapes or imported images changed the canvas as well, the strokes alone
+    // aren't the doodle then and the document can't be rendered
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub incomplete: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct LayerSettings {
+    #[serde(default = "default_opacity")]
+    pub opacity: f32,
+    #[serde(default = "default_visible")]
+    pub visible: bool,
+    #[serde(default)]
+    pub blend_mode: BlendMode,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct StrokeData {
+    #[serde(default)]
+    pub layer: usize,
+    // "#rrggbb" or "#rrggbbaa", sRGB with straight alpha
+    pub color: String,
+    pub size: f32,
+    // Exponent of the pressure curve
+    #[serde(default = "default_pressure_curve")]
+    pub pressure_curve: f32,
+    // x and y in canvas pixels, then the raw pressure
+    pub points: Vec<[f32; 3]>,
+}
+
+fn default_layers() -> Vec<LayerSettings> {
+    vec![LayerSettings::default()]
+}
+
+fn default_opacity() -> f32 {
+    1.0
+}
+
+fn default_visible() -> bool {
+    true
+}
+
+fn default_pressure_curve() -> f32 {
+    1.0
+}
+
+impl Default for LayerSettings {
+    fn default() -> Self {
+        Self {
+            opacity: default_opacity(),
+            visible: default_visible(),
+            blend_mode: BlendMode::default(),
+        }
+    }
+}
+
+impl From<&Stroke> for StrokeData {
+    fn from(stroke: &Stroke) -> Self {
+        Self {
+            layer: stroke.layer,
+            color: color::to_hex(stroke.color),
+            size: stroke.size,
+            pressure_curve: stroke.pressure_curve.exponent,
+            points: stroke
+                .points
+                .iter()
+                .map(|point| [point.x, point.y, point.pressure])
+                .collect(),
+        }
+    }
+}
+
+impl StrokeData {
+    pub fn to_stroke(&self) -> anyhow::Result<Stroke> {
+        let color = color::from_hex(&self.color)
+            .ok_or_else(|| anyhow!("Invalid stroke color {:?}", self.color))?;
+        let mut stroke = Stroke::new(
+            self.layer,
+            color,
+            self.size.max(1.0),
+            PressureCurve {
+                exponent: self.pressure_curve.max(0.01),
+            },
+        );
+        for &[x, y, pressure] in &self.points {
+            stroke.push(StrokePoint { x, y, pressure });
+        }
+        Ok(stroke)
+    }
+}
+
+impl StrokeDocument {
+    pub fn from_json(json: &str) -> anyhow::Result<Self> {
+        let document: Self = serde_json::from_str(json).context("Invalid stroke document")?;
+        document.validate()?;
+        Ok(document)
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("Stroke documents always serialize")
+    }
+
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if utils::clamp_canvas_size(self.width, self.height) != (self.width, self.height) {
+            bail!(
+                "Canvas size {}x{} is outside of {}..={}",
+                self.width,
+                self.height,
+                utils::MIN_CANVAS_SIZE,
+                utils::MAX_CANVAS_SIZE
+            );
+        }
+        if self.layers.is_empty() {
+            bail!("A document needs at least one layer");
+        }
+        if let Some(stroke) = self
+            .strokes
+            .iter()
+            .find(|stroke| stroke.layer >= self.layers.len())
+        {
+            bail!("Stroke drawn on missing layer {}", stroke.layer);
+        }
+        Ok(())
+    }
+
+    // Fails for documents that are missing some of the edits made to the canvas
+    pub fn ensure_complete(&self) -> anyhow::Result<()> {
+        if self.incomplete {
+            bail!("The doodle has fills, shapes or images that aren't recorded as strokes");
+        }
+        Ok(())
+    }
+
+    // The same doodle with every coordinate and brush size multiplied by `scale`
+    pub fn scaled(&self, scale: f32) -> Self {
+        let scale_size = |size: u32| ((size as f32 * scale).round() as u32).max(1);
+        Self {
+            width: scale_size(self.width),
+            height: scale_size(self.height),
+            layers: self.layers.clone(),
+            background: self.background,
+            incomplete: self.incomplete,
+            strokes: self
+                .strokes
+                .iter()
+                .map(|stroke| StrokeData {
+                    size: stroke.size * scale,
+                    points: stroke
+                        .points
+                        .iter()
+                        .map(|&[x, y, pressure]| [x * scale, y * scale, pressure])
+                        .collect(),
+                    ..stroke.clone()
+                })
+                .collect(),
+        }
+    }
+
+    // Sets up the layers of a freshly created state and draws every stroke into them
+    #[cfg(feature = "render")]
+    pub fn draw(&self, state: &mut State) -> anyhow::Result<()> {
+        self.ensure_complete()?;
+        state.set_background(self.background);
+        for (index, layer) in self.layers.iter().enumerate() {
+            if index > 0 {
+                state.apply_layer_command(LayerCommand::Add);
+            }
+            state.apply_layer_command(LayerCommand::SetOpacity(index, layer.opacity));
+            state.apply_layer_command(LayerCommand::SetVisible(index, layer.visible));
+            state.apply_layer_command(LayerCommand::SetBlendMode(index, layer.blend_mode));
+        }
+        for stroke in &self.strokes {
+            state.draw_stroke(&stroke.to_stroke()?);
+        }
+        Ok(())
+    }
+
+    // Renders the document without a GPU, the result matches `draw` followed by `State::export`
+    pub fn rasterize(&self) -> anyhow::Result<image::RgbaImage> {
+        self.ensure_complete()?;
+        let mut canvas = CpuCanvas::new(self.width, self.height);
+        canvas.set_background(self.background);
+        for (index, layer) in self.layers.iter().enumerate() {
+            if index > 0 {
+                canvas.add_layer(layer.opacity, layer.visible, layer.blend_mode);
+            } else {
+                canvas.set_layer(index, layer.opacity, layer.visible, layer.blend_mode);
+            }
+        }
+        for stroke in &self.strokes {
+            canvas.draw_stroke(&stroke.to_stroke()?);
+        }
+        Ok(canvas.export())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn missing_fields_use_defaults() {
+        let document = StrokeDocument::from_json(
+            r##"{ "width": 100, "height": 80,
+                  "strokes": [{ "color": "#102030", "size": 4, "points": [[1, 2, 0.5]] }] }"##,
+        )
+        .unwrap();
+        assert_eq!(document.layers, vec![LayerSettings::default()]);
+        assert_eq!(document.background, Background::default());
+        let stroke = document.strokes[0].to_stroke().unwrap();
+        assert_eq!(stroke.layer, 0);
+        assert_eq!(stroke.color, image::Rgba([0x10, 0x20, 0x30, 0xff]));
+        assert_eq!(stroke.pressure_curve, PressureCurve::default());
+        assert_eq!(
+            stroke.points,
+            vec![StrokePoint {
+                x: 1.0,
+                y: 2.0,
+                pressure: 0.5
+            }]
+        );
+    }
+
+    #[test]
+    fn strokes_round_trip() {
+        let mut stroke = Stroke::new(
+            1,
+            image::Rgba([200, 100, 50, 128]),
+            7.5,
+            PressureCurve { exponent: 2.0 },
+        );
+        stroke.push(StrokePoint {
+            x: 3.0,
+            y: 4.0,
+            pressure: 0.25,
+        });
+        let document = StrokeDocument {
+            width: 64,
+            height: 64,
+            layers: vec![LayerSettings::default(); 2],
+            strokes: vec![StrokeData::from(&stroke)],
+            background: Background::Transparent,
+            incomplete: false,
+        };
+        let parsed = StrokeDocument::from_json(&document.to_json()).unwrap();
+        assert_eq!(parsed, document);
+        let replayed = parsed.strokes[0].to_stroke().unwrap();
+        assert_eq!(replayed.color, stroke.color);
+        assert_eq!(replayed.points, stroke.points);
+    }
+
+    #[test]
+    fn invalid_documents_are_rejected() {
+        assert!(StrokeDocument::from_json(r#"{ "width": 10, "height": 80 }"#).is_err());
+        assert!(StrokeDocument::from_json(
+            r##"{ "width": 100, "height": 80, "strokes": [{ "layer": 1, "color": "#000000", "size": 1, "points": [] }] }"##
+        )
+        .is_err());
+        let document = StrokeDocument::from_json(
+            r#"{ "width": 100, "height": 80, "strokes": [{ "color": "red", "size": 1, "points": [] }] }"#,
+        )
+        .unwrap();
+        assert!(document.strokes[0].to_stroke().is_err());
+    }
+
+    #[test]
+    fn incomplete_documents_are_not_rendered() {
+        let document =
+            StrokeDocument::from_json(r#"{ "width": 100, "height": 80, "incomplete": true }"#)
+                .unwrap();
+        assert!(document.to_json().contains(r#""incomplete":true"#));
+        assert!(document.scaled(2.0).incomplete);
+        assert!(document.rasterize().is_err());
+        let complete = StrokeDocument::from_json(r#"{ "width": 100, "height": 80 }"#).unwrap();
+        assert!(!complete.to_json().contains("incomplete"));
+        assert!(complete.rasterize().is_ok());
+    }
+
+    #[test]
+    fn scaling_moves_points_and_sizes() {
+        let document = StrokeDocument::from_json(
+            r##"{ "width": 100, "height": 80,
+                  "strokes": [{ "color": "#000000", "size": 4, "points": [[10, 20, 0.5]] }] }"##,
+        )
+        .unwrap()
+        .scaled(2.5);
+        assert_eq!((document.width, document.height), (250, 200));
+        assert_eq!(document.strokes[0].size, 10.0);
+        assert_eq!(document.strokes[0].points, vec![[25.0, 50.0, 0.5]]);
+    }
+}
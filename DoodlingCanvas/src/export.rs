@@ -0,0 +1,222 @@
+use anyhow::{bail, Context};
+use image::{
+    codecs::{jpeg::JpegEncoder, webp::WebPEncoder},
+    imageops, Rgba, RgbaImage,
+};
+
+use crate::{color, layers::Background};
+
+#[cfg(target_arch = "wasm32")]
+use wasm_bindgen::prelude::*;
+
+/// File format of an exported canvas
+#[cfg_attr(target_arch = "wasm32", wasm_bindgen)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ExportFormat {
+    // Lossless, tagged sRGB
+    #[default]
+    Png,
+    // Lossy and without alpha, transparent parts are put on white
+    Jpeg,
+    // Lossless, the encoder doesn't do lossy WebP
+    WebP,
+}
+
+impl ExportFormat {
+    // In the order of the discriminants the page sees
+    pub const ALL: [ExportFormat; 3] = [ExportFormat::Png, ExportFormat::Jpeg, ExportFormat::WebP];
+}
+
+/// How the canvas is turned into a file
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ExportOptions {
+    pub format: ExportFormat,
+    // 1 to 100, only used by JPEG
+    pub quality: u8,
+    // Multiplies both dimensions, after cropping
+    pub scale: f32,
+    // Drops the rows and columns around the drawing that only show the background
+    pub crop_to_content: bool,
+}
+
+impl Default for ExportOptions {
+    fn default() -> Self {
+        Self {
+            format: ExportFormat::default(),
+            quality: 90,
+            scale: 1.0,
+            crop_to_content: false,
+        }
+    }
+}
+
+// Exports can't be larger than this many times the largest canvas
+pub const MAX_SCALE: f32 = 4.0;
+
+#[cfg(target_arch = "wasm32")]
+impl ExportOptions {
+    /// Reads `{ format, quality, scale, crop }` where every field is optional:
+    /// `format` is an `ExportFormat`, `quality` 1 to 100 and `crop` crops to the drawing
+    pub fn from_js(options: &JsValue) -> Self {
+        let get = |key: &str| {
+            if options.is_object() {
+                js_sys::Reflect::get(options, &JsValue::from_str(key)).unwrap_or(JsValue::UNDEFINED)
+            } else {
+                JsValue::UNDEFINED
+            }
+        };
+        let defaults = Self::default();
+        Self {
+            format: get("format")
+                .as_f64()
+                .and_then(|format| ExportFormat::ALL.get(format as usize).copied())
+                .unwrap_or_default(),
+            quality: get("quality")
+                .as_f64()
+                .map_or(defaults.quality, |quality| quality.clamp(1.0, 100.0) as u8),
+            scale: get("scale")
+                .as_f64()
+                .map_or(defaults.scale, |scale| scale as f32),
+            crop_to_content: get("crop").is_truthy(),
+        }
+    }
+}
+
+// The smallest rectangle (x, y, width, height) holding every pixel that isn't the background,
+// None when nothing was drawn. The image is an export, sRGB with straight alpha
+pub fn content_bounds(image: &RgbaImage, background: Background) -> Option<(u32, u32, u32, u32)> {
+    let background = color::from_layer_pixel(background.to_layer_pixel());
+    // The GPU may round the background differently from the CPU
+    let is_background = |pixel: &Rgba<u8>| {
+        pixel
+            .0
+            .iter()
+            .zip(background.0)
+            .all(|(&channel, expected)| channel.abs_diff(expected) <= 1)
+    };
+    let (mut min_x, mut min_y, mut max_x, mut max_y) = (u32::MAX, u32::MAX, 0, 0);
+    for (x, y, pixel) in image.enumerate_pixels() {
+        if !is_background(pixel) {
+            min_x = min_x.min(x);
+            min_y = min_y.min(y);
+            max_x = max_x.max(x);
+            max_y = max_y.max(y);
+        }
+    }
+    (min_x <= max_x).then(|| (min_x, min_y, max_x - min_x + 1, max_y - min_y + 1))
+}
+
+// Crops, scales and encodes an exported canvas
+pub fn encode(
+    image: RgbaImage,
+    background: Background,
+    options: &ExportOptions,
+) -> anyhow::Result<Vec<u8>> {
+    if !(options.scale > 0.0 && options.scale <= MAX_SCALE) {
+        bail!("The export scale must be above 0 and at most {}", MAX_SCALE);
+    }
+    let mut image = image;
+    if options.crop_to_content {
+        // An empty canvas is exported whole
+        if let Some((x, y, width, height)) = content_bounds(&image, background) {
+            image = imageops::crop_imm(&image, x, y, width, height).to_image();
+        }
+    }
+    if options.scale != 1.0 {
+        let scale_size = |size: u32| ((size as f32 * options.scale).round() as u32).max(1);
+        image = imageops::resize(
+            &image,
+            scale_size(image.width()),
+            scale_size(image.height()),
+            imageops::FilterType::Triangle,
+        );
+    }
+    let mut buffer = Vec::new();
+    match options.format {
+        ExportFormat::Png => buffer = color::encode_png(&image)?,
+        ExportFormat::Jpeg => {
+            let mut opaque = RgbaImage::from_pixel(image.width(), image.height(), Rgba([255; 4]));
+            imageops::overlay(&mut opaque, &image, 0, 0);
+            let opaque = image::DynamicImage::ImageRgba8(opaque).to_rgb8();
+            opaque
+                .write_with_encoder(JpegEncoder::new_with_quality(
+                    &mut buffer,
+                    options.quality.clamp(1, 100),
+                ))
+                .context("Failed to encode the JPEG")?;
+        }
+        ExportFormat::WebP => image
+            .write_with_encoder(WebPEncoder::new_lossless(&mut buffer))
+            .context("Failed to encode the WebP")?,
+    }
+    Ok(buffer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RED: Rgba<u8> = Rgba([255, 0, 0, 255]);
+
+    fn doodle(background: Rgba<u8>) -> RgbaImage {
+        let mut image = RgbaImage::from_pixel(40, 30, background);
+        for (x, y) in [(10, 5), (25, 20)] {
+            image.put_pixel(x, y, RED);
+        }
+        image
+    }
+
+    #[test]
+    fn content_bounds_ignore_the_background() {
+        let grey = Background::default();
+        let image = doodle(Rgba([124, 124, 124, 255]));
+        assert_eq!(content_bounds(&image, grey), Some((10, 5, 16, 16)));
+        assert_eq!(
+            content_bounds(&doodle(Rgba([0; 4])), Background::Transparent),
+            Some((10, 5, 16, 16))
+        );
+        let empty = RgbaImage::from_pixel(8, 8, Rgba([124, 124, 124, 255]));
+        assert_eq!(content_bounds(&empty, grey), None);
+    }
+
+    #[test]
+    fn crops_then_scales() {
+        let options = ExportOptions {
+            scale: 2.0,
+            crop_to_content: true,
+            ..ExportOptions::default()
+        };
+        let bytes = encode(doodle(Rgba([0; 4])), Background::Transparent, &options).unwrap();
+        let exported = image::load_from_memory(&bytes).unwrap();
+        assert_eq!((exported.width(), exported.height()), (32, 32));
+    }
+
+    #[test]
+    fn formats_decode_back() {
+        for format in ExportFormat::ALL {
+            let options = ExportOptions {
+                format,
+                ..ExportOptions::default()
+            };
+            let bytes = encode(doodle(Rgba([0; 4])), Background::Transparent, &options).unwrap();
+            let decoded = image::load_from_memory(&bytes).unwrap().to_rgba8();
+            assert_eq!(decoded.dimensions(), (40, 30));
+            match format {
+                // Transparent pixels are on white
+                ExportFormat::Jpeg => assert!(decoded.get_pixel(0, 0).0.iter().all(|&c| c > 250)),
+                _ => assert_eq!(decoded, doodle(Rgba([0; 4]))),
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_bad_scales() {
+        for scale in [0.0, -1.0, f32::NAN, 5.0] {
+            let options = ExportOptions {
+                scale,
+                ..ExportOptions::default()
+            };
+            assert!(encode(doodle(Rgba([0; 4])), Background::Transparent, &options).is_err());
+        }
+    }
+}
@@ -0,0 +1,62 @@
+use image::{Rgba, RgbaImage};
+
+// Largest difference between any channel of the two colors
+fn distance(a: &Rgba<u8>, b: &Rgba<u8>) -> u8 {
+    a.0.iter()
+        .zip(b.0.iter())
+        .map(|(a, b)| a.abs_diff(*b))
+        .max()
+        .unwrap_or(0)
+}
+
+/// Paints the contiguous region around `start` whose colors are within `tolerance` of the color at `start`.
+/// Returns false when nothing was changed.
+pub fn flood_fill(
+    image: &mut RgbaImage,
+    start: (u32, u32),
+    color: Rgba<u8>,
+    tolerance: u8,
+) -> bool {
+    let (width, height) = image.dimensions();
+    if start.0 >= width || start.1 >= height {
+        return false;
+    }
+    let target = *image.get_pixel(start.0, start.1);
+    if distance(&target, &color) == 0 {
+        return false;
+    }
+    let mut visited = vec![false; (width * height) as usize];
+    let mut matches = |image: &RgbaImage, x: u32, y: u32| {
+        let index = (y * width + x) as usize;
+        let inside = !visited[index] && distance(image.get_pixel(x, y), &target) <= tolerance;
+        if inside {
+            visited[index] = true;
+        }
+        inside
+    };
+    // Scanline fill, every seed fills its whole row then queues the rows above and below
+    let mut seeds = vec![start];
+    while let Some((x, y)) = seeds.pop() {
+        if !matches(image, x, y) {
+            continue;
+        }
+        let mut left = x;
+        while left > 0 && matches(image, left - 1, y) {
+            left -= 1;
+        }
+        let mut right = x;
+        while right + 1 < width && matches(image, right + 1, y) {
+            right += 1;
+        }
+        for x in left..=right {
+            image.put_pixel(x, y, color);
+            if y > 0 {
+                seeds.push((x, y - 1));
+            }
+            if y + 1 < height {
+                seeds.push((x, y + 1));
+            }
+        }
+    }
+    true
+}
@@ -0,0 +1,201 @@
+use std::{
+    cell::RefCell,
+    sync::atomic::{AtomicU32, Ordering},
+};
+use web_time::Instant;
+
+use anyhow::Context;
+use winit::{
+    application::ApplicationHandler,
+    event_loop::{ActiveEventLoop, ControlFlow, EventLoop, EventLoopClosed, EventLoopProxy},
+    window::WindowId,
+};
+
+use crate::winit_app::{CanvasApp, Events};
+
+pub type WidgetId = u32;
+
+/// winit allows a single event loop per process (or page), every canvas widget runs in this one
+/// with its own window and state.
+pub enum HostEvent {
+    // A widget started after the loop was already running
+    Add(WidgetId, Box<CanvasApp>),
+    Widget(WidgetId, Events),
+}
+
+impl std::fmt::Debug for HostEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HostEvent::Add(id, _) => f.debug_tuple("Add").field(id).finish(),
+            HostEvent::Widget(id, event) => f.debug_tuple("Widget").field(id).field(event).finish(),
+        }
+    }
+}
+
+thread_local! {
+    // Built for the first widget, taken by the first one to run
+    static EVENT_LOOP: RefCell<Option<EventLoop<HostEvent>>> = const { RefCell::new(None) };
+    static PROXY: RefCell<Option<EventLoopProxy<HostEvent>>> = const { RefCell::new(None) };
+}
+
+static NEXT_WIDGET_ID: AtomicU32 = AtomicU32::new(0);
+
+/// Sends events to one widget of the shared loop
+#[derive(Clone)]
+pub struct WidgetProxy {
+    id: WidgetId,
+    proxy: EventLoopProxy<HostEvent>,
+}
+
+impl WidgetProxy {
+    pub fn id(&self) -> WidgetId {
+        self.id
+    }
+
+    pub fn send_event(&self, event: Events) -> Result<(), EventLoopClosed<HostEvent>> {
+        self.proxy.send_event(HostEvent::Widget(self.id, event))
+    }
+}
+
+// Registers a new widget, building the shared loop if there is none yet
+pub fn widget_proxy() -> anyhow::Result<WidgetProxy> {
+    let proxy = PROXY.with_borrow_mut(|proxy| -> anyhow::Result<_> {
+        if proxy.is_none() {
+            let event_loop = EventLoop::<HostEvent>::with_user_event()
+                .build()
+                .context("Failed to create event loop")?;
+            *proxy = Some(event_loop.create_proxy());
+            EVENT_LOOP.set(Some(event_loop));
+        }
+        Ok(proxy.clone().unwrap())
+    })?;
+    Ok(WidgetProxy {
+        id: NEXT_WIDGET_ID.fetch_add(1, Ordering::Relaxed),
+        proxy,
+    })
+}
+
+// The first widget to run starts the loop, the others join it.
+// On the desktop this only returns once the loop exits
+pub fn run(id: WidgetId, app: CanvasApp) {
+    let Some(event_loop) = EVENT_LOOP.take() else {
+        let proxy = PROXY.with_borrow(|proxy| proxy.clone());
+        let sent = proxy.map(|proxy| proxy.send_event(HostEvent::Add(id, Box::new(app))));
+        if !matches!(sent, Some(Ok(()))) {
+            log::warn!("The event loop is gone, widget {} can't be started", id);
+        }
+        return;
+    };
+    #[allow(unused_mut)]
+    let mut host = CanvasHost {
+        widgets: vec![(id, app)],
+        waiting: Vec::new(),
+        closed: Vec::new(),
+        resumed: false,
+    };
+    #[cfg(target_arch = "wasm32")]
+    {
+        use winit::platform::web::EventLoopExtWebSys;
+        event_loop.spawn_app(host);
+    }
+    #[cfg(not(target_arch = "wasm32"))]
+    if let Err(err) = event_loop.run_app(&mut host) {
+        log::error!("Event loop failed: {}", err);
+    }
+}
+
+struct CanvasHost {
+    widgets: Vec<(WidgetId, CanvasApp)>,
+    // Events sent to widgets that haven't joined the loop yet, e.g. the tool set up before running it
+    waiting: Vec<(WidgetId, Events)>,
+    // Events for these are dropped, the widget may have been closed before it joined
+    closed: Vec<WidgetId>,
+    resumed: bool,
+}
+
+impl CanvasHost {
+    // Drops the widget's window and state. On the desktop the loop exits with the last widget,
+    // a page keeps it running for the widgets it creates later
+    #[cfg_attr(target_arch = "wasm32", allow(unused_variables))]
+    fn close(&mut self, event_loop: &ActiveEventLoop, id: WidgetId) {
+        self.waiting.retain(|(widget, _)| *widget != id);
+        self.closed.push(id);
+        if let Some(index) = self.widgets.iter().position(|(widget, _)| *widget == id) {
+            let (_, mut app) = self.widgets.remove(index);
+            app.close();
+        }
+        #[cfg(not(target_arch = "wasm32"))]
+        if self.widgets.is_empty() {
+            event_loop.exit();
+        }
+    }
+}
+
+impl ApplicationHandler<HostEvent> for CanvasHost {
+    fn resumed(&mut self, event_loop: &ActiveEventLoop) {
+        self.resumed = true;
+        for (_, app) in &mut self.widgets {
+            app.resumed(event_loop);
+        }
+    }
+
+    // The canvas may not come back after a suspend, so the snapshots can't wait
+    fn suspended(&mut self, _event_loop: &ActiveEventLoop) {
+        for (_, app) in &self.widgets {
+            app.refresh_snapshot(None);
+        }
+    }
+
+    // Wakes the loop up when the next snapshot is due
+    fn about_to_wait(&mut self, event_loop: &ActiveEventLoop) {
+        let now = Instant::now();
+        let next = self
+            .widgets
+            .iter()
+            .filter_map(|(_, app)| app.refresh_snapshot(Some(now)))
+            .min();
+        event_loop.set_control_flow(next.map_or(ControlFlow::Wait, ControlFlow::WaitUntil));
+    }
+
+    fn window_event(
+        &mut self,
+        event_loop: &ActiveEventLoop,
+        window_id: WindowId,
+        event: winit::event::WindowEvent,
+    ) {
+        if let Some((_, app)) = self
+            .widgets
+            .iter_mut()
+            .find(|(_, app)| app.window_id() == Some(window_id))
+        {
+            app.window_event(event_loop, window_id, event);
+        }
+    }
+
+    fn user_event(&mut self, event_loop: &ActiveEventLoop, event: HostEvent) {
+        match event {
+            HostEvent::Add(id, _) if self.closed.contains(&id) => {}
+            HostEvent::Add(id, mut app) => {
+                if self.resumed {
+                    app.resumed(event_loop);
+                }
+                let (events, waiting) = std::mem::take(&mut self.waiting)
+                    .into_iter()
+                    .partition(|(widget, _)| *widget == id);
+                self.waiting = waiting;
+                for (_, event) in events {
+                    app.user_event(event_loop, event);
+                }
+                self.widgets.push((id, *app));
+            }
+            HostEvent::Widget(id, Events::Close) => self.close(event_loop, id),
+            HostEvent::Widget(id, _) if self.closed.contains(&id) => {}
+            HostEvent::Widget(id, event) => {
+                match self.widgets.iter_mut().find(|(widget, _)| *widget == id) {
+                    Some((_, app)) => app.user_event(event_loop, event),
+                    None => self.waiting.push((id, event)),
+                }
+            }
+        }
+    }
+}
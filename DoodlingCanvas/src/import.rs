@@ -0,0 +1,145 @@
+use anyhow::Context;
+use image::{imageops, RgbaImage};
+
+#[cfg(target_arch = "wasm32")]
+use wasm_bindgen::prelude::*;
+
+/// How an image that doesn't have the size of the canvas is placed on it.
+/// Parts of the canvas the image doesn't cover become transparent
+#[cfg_attr(target_arch = "wasm32", wasm_bindgen)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ImageFit {
+    // Scaled to the canvas size, the aspect ratio isn't kept
+    Stretch,
+    // Scaled to fit inside the canvas and centered
+    #[default]
+    Contain,
+    // Scaled to cover the whole canvas and centered, what overflows is cropped
+    Cover,
+    // Centered at its own size
+    Center,
+}
+
+impl ImageFit {
+    // In the order of the discriminants the page sees
+    pub const ALL: [ImageFit; 4] = [
+        ImageFit::Stretch,
+        ImageFit::Contain,
+        ImageFit::Cover,
+        ImageFit::Center,
+    ];
+}
+
+/// An image waiting to be written into the active layer, sRGB with straight alpha
+pub struct ImageImport {
+    pub image: RgbaImage,
+    pub fit: ImageFit,
+}
+
+// The pixels aren't worth logging
+impl std::fmt::Debug for ImageImport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "ImageImport({}x{}, {:?})",
+            self.image.width(),
+            self.image.height(),
+            self.fit
+        )
+    }
+}
+
+// Any format the image crate was built with, PNG and JPEG at least
+pub fn decode(bytes: &[u8]) -> anyhow::Result<RgbaImage> {
+    Ok(image::load_from_memory(bytes)
+        .context("Failed to decode the image")?
+        .to_rgba8())
+}
+
+// The image placed on a transparent image of the canvas size
+pub fn fit_to_canvas(image: &RgbaImage, (width, height): (u32, u32), fit: ImageFit) -> RgbaImage {
+    if image.dimensions() == (width, height) {
+        return image.clone();
+    }
+    let (image_width, image_height) = (image.width() as f32, image.height() as f32);
+    let scale = match fit {
+        ImageFit::Stretch => {
+            return imageops::resize(image, width, height, imageops::FilterType::Triangle)
+        }
+        ImageFit::Contain => (width as f32 / image_width).min(height as f32 / image_height),
+        ImageFit::Cover => (width as f32 / image_width).max(height as f32 / image_height),
+        ImageFit::Center => 1.0,
+    };
+    let scaled_width = ((image_width * scale).round() as u32).max(1);
+    let scaled_height = ((image_height * scale).round() as u32).max(1);
+    let mut canvas = RgbaImage::new(width, height);
+    let scaled;
+    let image = if (scaled_width, scaled_height) == image.dimensions() {
+        image
+    } else {
+        scaled = imageops::resize(
+            image,
+            scaled_width,
+            scaled_height,
+            imageops::FilterType::Triangle,
+        );
+        &scaled
+    };
+    imageops::replace(
+        &mut canvas,
+        image,
+        (width as i64 - scaled_width as i64) / 2,
+        (height as i64 - scaled_height as i64) / 2,
+    );
+    canvas
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Rgba;
+
+    const RED: Rgba<u8> = Rgba([255, 0, 0, 255]);
+
+    fn covered(image: &RgbaImage) -> (u32, u32, u32, u32) {
+        let covered: Vec<(u32, u32)> = image
+            .enumerate_pixels()
+            .filter(|(_, _, pixel)| pixel.0[3] > 0)
+            .map(|(x, y, _)| (x, y))
+            .collect();
+        let min_x = covered.iter().map(|&(x, _)| x).min().unwrap();
+        let max_x = covered.iter().map(|&(x, _)| x).max().unwrap();
+        let min_y = covered.iter().map(|&(_, y)| y).min().unwrap();
+        let max_y = covered.iter().map(|&(_, y)| y).max().unwrap();
+        (min_x, min_y, max_x, max_y)
+    }
+
+    #[test]
+    fn contain_letterboxes_the_image() {
+        let image = RgbaImage::from_pixel(20, 10, RED);
+        let fitted = fit_to_canvas(&image, (40, 40), ImageFit::Contain);
+        assert_eq!(fitted.dimensions(), (40, 40));
+        assert_eq!(covered(&fitted), (0, 10, 39, 29));
+    }
+
+    #[test]
+    fn cover_fills_the_canvas() {
+        let image = RgbaImage::from_pixel(20, 10, RED);
+        let fitted = fit_to_canvas(&image, (40, 40), ImageFit::Cover);
+        assert!(fitted.pixels().all(|pixel| *pixel == RED));
+    }
+
+    #[test]
+    fn center_crops_and_keeps_the_size() {
+        let image = RgbaImage::from_pixel(60, 10, RED);
+        let fitted = fit_to_canvas(&image, (40, 40), ImageFit::Center);
+        assert_eq!(covered(&fitted), (0, 15, 39, 24));
+    }
+
+    #[test]
+    fn stretch_ignores_the_aspect_ratio() {
+        let image = RgbaImage::from_pixel(20, 10, RED);
+        let fitted = fit_to_canvas(&image, (40, 40), ImageFit::Stretch);
+        assert!(fitted.pixels().all(|pixel| *pixel == RED));
+    }
+}
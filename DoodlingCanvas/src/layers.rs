@@ -0,0 +1,386 @@
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "render")]
+use wgpu::util::DeviceExt;
+
+use crate::color;
+
+#[cfg(target_arch = "wasm32")]
+use wasm_bindgen::prelude::*;
+
+/// How a layer is combined with the layers below it when compositing.
+/// Layer textures hold premultiplied colors, so every mode is expressed with premultiplied blend factors.
+#[cfg_attr(target_arch = "wasm32", wasm_bindgen)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BlendMode {
+    #[default]
+    Normal,
+    Multiply,
+    Screen,
+    Add,
+}
+
+impl BlendMode {
+    pub const ALL: [BlendMode; 4] = [
+        BlendMode::Normal,
+        BlendMode::Multiply,
+        BlendMode::Screen,
+        BlendMode::Add,
+    ];
+
+    #[cfg(feature = "render")]
+    pub fn blend_state(self) -> wgpu::BlendState {
+        let alpha = wgpu::BlendComponent {
+            src_factor: wgpu::BlendFactor::One,
+            dst_factor: wgpu::BlendFactor::OneMinusSrcAlpha,
+            operation: wgpu::BlendOperation::Add,
+        };
+        let color = match self {
+            BlendMode::Normal => alpha,
+            // src * dst + dst * (1 - src_alpha)
+            BlendMode::Multiply => wgpu::BlendComponent {
+                src_factor: wgpu::BlendFactor::Dst,
+                dst_factor: wgpu::BlendFactor::OneMinusSrcAlpha,
+                operation: wgpu::BlendOperation::Add,
+            },
+            // src + dst * (1 - src)
+            BlendMode::Screen => wgpu::BlendComponent {
+                src_factor: wgpu::BlendFactor::One,
+                dst_factor: wgpu::BlendFactor::OneMinusSrc,
+                operation: wgpu::BlendOperation::Add,
+            },
+            BlendMode::Add => wgpu::BlendComponent {
+                src_factor: wgpu::BlendFactor::One,
+                dst_factor: wgpu::BlendFactor::One,
+                operation: wgpu::BlendOperation::Add,
+            },
+        };
+        wgpu::BlendState { color, alpha }
+    }
+}
+
+/// What the layers are composited onto. It isn't part of any layer, so it can be changed at any time.
+/// Written as "transparent" or a "#rrggbb" color in documents and by the page
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum Background {
+    // sRGB with straight alpha
+    Color(image::Rgba<u8>),
+    // Shown as a checkerboard, exported as transparent pixels
+    Transparent,
+}
+
+impl Default for Background {
+    // The dark grey the canvas always had, 0.2 in linear space
+    fn default() -> Self {
+        Background::Color(image::Rgba([124, 124, 124, 255]))
+    }
+}
+
+impl Background {
+    // Premultiplied linear color, what the compositing passes start from
+    pub fn to_linear(self) -> [f32; 4] {
+        match self {
+            Background::Color(color) => {
+                let [r, g, b, a] = color::to_linear(color);
+                [r * a, g * a, b * a, a]
+            }
+            Background::Transparent => [0.0; 4],
+        }
+    }
+
+    // The background as a pixel of a layer texture
+    pub fn to_layer_pixel(self) -> image::Rgba<u8> {
+        match self {
+            Background::Color(color) => color::to_layer_pixel(color),
+            Background::Transparent => image::Rgba([0, 0, 0, 0]),
+        }
+    }
+}
+
+impl std::str::FromStr for Background {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        if value.eq_ignore_ascii_case("transparent") {
+            return Ok(Background::Transparent);
+        }
+        color::from_hex(value)
+            .map(Background::Color)
+            .ok_or_else(|| format!("Invalid background {:?}", value))
+    }
+}
+
+impl TryFrom<String> for Background {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<Background> for String {
+    fn from(background: Background) -> Self {
+        match background {
+            Background::Color(color) => color::to_hex(color),
+            Background::Transparent => "transparent".to_owned(),
+        }
+    }
+}
+
+/// Changes to the layer stack requested from outside the event loop
+#[derive(Debug, Clone, Copy)]
+pub enum LayerCommand {
+    Add,
+    Remove(usize),
+    Move { from: usize, to: usize },
+    Select(usize),
+    SetOpacity(usize, f32),
+    SetVisible(usize, bool),
+    SetBlendMode(usize, BlendMode),
+}
+
+impl LayerCommand {
+    // Where the layer at `index` ends up once the command is applied to a stack of `count` layers
+    // with `active` selected, None when it is removed. Mirrors `State::apply_layer_command`
+    pub fn remap_index(self, index: usize, count: usize, active: usize) -> Option<usize> {
+        match self {
+            LayerCommand::Add if index > active => Some(index + 1),
+            LayerCommand::Remove(removed) if removed < count && count > 1 => {
+                match index.cmp(&removed) {
+                    std::cmp::Ordering::Less => Some(index),
+                    std::cmp::Ordering::Equal => None,
+                    std::cmp::Ordering::Greater => Some(index - 1),
+                }
+            }
+            LayerCommand::Move { from, to } if from < count && to < count => {
+                if index == from {
+                    Some(to)
+                } else if from < index && index <= to {
+                    Some(index - 1)
+                } else if to <= index && index < from {
+                    Some(index + 1)
+                } else {
+                    Some(index)
+                }
+            }
+            _ => Some(index),
+        }
+    }
+}
+
+#[cfg(feature = "render")]
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+struct LayerUniform {
+    // vec4 to keep the uniform 16 byte aligned for WebGL, only x is used
+    opacity: [f32; 4],
+}
+
+#[cfg(feature = "render")]
+pub struct Layer {
+    pub texture: wgpu::Texture,
+    pub bind_group: wgpu::BindGroup,
+    uniform_buffer: wgpu::Buffer,
+    pub opacity: f32,
+    pub visible: bool,
+    pub blend_mode: BlendMode,
+}
+
+#[cfg(feature = "render")]
+impl Layer {
+    pub const FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba8UnormSrgb;
+
+    pub fn new(
+        device: &wgpu::Device,
+        layout: &wgpu::BindGroupLayout,
+        sampler: &wgpu::Sampler,
+        size: wgpu::Extent3d,
+    ) -> Self {
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            size,
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: Self::FORMAT,
+            usage: wgpu::TextureUsages::COPY_SRC
+                | wgpu::TextureUsages::RENDER_ATTACHMENT
+                | wgpu::TextureUsages::TEXTURE_BINDING
+                | wgpu::TextureUsages::COPY_DST,
+            label: Some("Layer Texture"),
+            view_formats: &[Self::FORMAT],
+        });
+        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+        let uniform_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Layer Uniform Buffer"),
+            contents: bytemuck::cast_slice(&[LayerUniform {
+                opacity: [1.0, 0.0, 0.0, 0.0],
+            }]),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        });
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(&view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::Sampler(sampler),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: uniform_buffer.as_entire_binding(),
+                },
+            ],
+            label: Some("Layer Bind Group"),
+        });
+        Self {
+            texture,
+            bind_group,
+            uniform_buffer,
+            opacity: 1.0,
+            visible: true,
+            blend_mode: BlendMode::Normal,
+        }
+    }
+
+    pub fn view(&self) -> wgpu::TextureView {
+        self.texture
+            .create_view(&wgpu::TextureViewDescriptor::default())
+    }
+
+    // Copies the layer's pixels into a new texture
+    pub fn snapshot(
+        &self,
+        device: &wgpu::Device,
+        encoder: &mut wgpu::CommandEncoder,
+    ) -> wgpu::Texture {
+        let snapshot = device.create_texture(&wgpu::TextureDescriptor {
+            size: self.texture.size(),
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: Self::FORMAT,
+            usage: wgpu::TextureUsages::COPY_SRC | wgpu::TextureUsages::COPY_DST,
+            label: Some("Layer Snapshot"),
+            view_formats: &[],
+        });
+        encoder.copy_texture_to_texture(
+            self.texture.as_image_copy(),
+            snapshot.as_image_copy(),
+            self.texture.size(),
+        );
+        snapshot
+    }
+
+    pub fn restore(&self, encoder: &mut wgpu::CommandEncoder, snapshot: &wgpu::Texture) {
+        encoder.copy_texture_to_texture(
+            snapshot.as_image_copy(),
+            self.texture.as_image_copy(),
+            self.texture.size(),
+        );
+    }
+
+    pub fn set_opacity(&mut self, queue: &wgpu::Queue, opacity: f32) {
+        self.opacity = opacity.clamp(0.0, 1.0);
+        queue.write_buffer(
+            &self.uniform_buffer,
+            0,
+            bytemuck::cast_slice(&[LayerUniform {
+                opacity: [self.opacity, 0.0, 0.0, 0.0],
+            }]),
+        );
+    }
+
+    pub fn bind_group_layout(device: &wgpu::Device) -> wgpu::BindGroupLayout {
+        device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        multisampled: false,
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    // This should match the filterable field of the
+                    // corresponding Texture entry above.
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+            ],
+            label: Some("layer_bind_group_layout"),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backgrounds_round_trip_through_strings() {
+        for background in [
+            Background::default(),
+            Background::Transparent,
+            Background::Color(image::Rgba([255, 255, 255, 255])),
+        ] {
+            assert_eq!(String::from(background).parse(), Ok(background));
+        }
+        assert_eq!(
+            "#ffffff".parse(),
+            Ok(Background::Color(image::Rgba([255, 255, 255, 255])))
+        );
+        assert!("white".parse::<Background>().is_err());
+    }
+
+    #[test]
+    fn transparent_background_has_no_color() {
+        assert_eq!(Background::Transparent.to_linear(), [0.0; 4]);
+        assert_eq!(
+            Background::Transparent.to_layer_pixel(),
+            image::Rgba([0, 0, 0, 0])
+        );
+        assert_eq!(
+            Background::default().to_layer_pixel(),
+            image::Rgba([124, 124, 124, 255])
+        );
+    }
+
+    #[test]
+    fn layer_indices_follow_the_stack() {
+        // Added above the active layer
+        assert_eq!(LayerCommand::Add.remap_index(0, 2, 0), Some(0));
+        assert_eq!(LayerCommand::Add.remap_index(1, 2, 0), Some(2));
+        assert_eq!(LayerCommand::Remove(1).remap_index(1, 3, 0), None);
+        assert_eq!(LayerCommand::Remove(1).remap_index(2, 3, 0), Some(1));
+        // The last layer can't be removed
+        assert_eq!(LayerCommand::Remove(0).remap_index(0, 1, 0), Some(0));
+        let move_up = LayerCommand::Move { from: 0, to: 2 };
+        let moved: Vec<_> = (0..3)
+            .map(|index| move_up.remap_index(index, 3, 0))
+            .collect();
+        assert_eq!(moved, vec![Some(2), Some(0), Some(1)]);
+        let move_down = LayerCommand::Move { from: 2, to: 0 };
+        let moved: Vec<_> = (0..3)
+            .map(|index| move_down.remap_index(index, 3, 0))
+            .collect();
+        assert_eq!(moved, vec![Some(1), Some(2), Some(0)]);
+    }
+}
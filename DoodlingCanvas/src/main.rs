@@ -1,7 +1,38 @@
 #![allow(non_snake_case)]
-use DoodlingCanvas::create_window;
+use std::path::PathBuf;
+
+use DoodlingCanvas::{create_window, options::WidgetOptions, utils};
+
+const USAGE: &str = "Usage: DoodlingCanvas [--output <file.png>]
+Press Ctrl+S to save the canvas to the output file (default: doodle.png).";
+
+// Returns the output path, None when the arguments are invalid
+fn parse_args(mut args: impl Iterator<Item = String>) -> Option<PathBuf> {
+    let mut output = PathBuf::from(utils::DEFAULT_OUTPUT_PATH);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--output" | "-o" => output = args.next()?.into(),
+            _ => match arg.strip_prefix("--output=") {
+                Some(path) => output = path.into(),
+                None => return None,
+            },
+        }
+    }
+    Some(output)
+}
 
 fn main() {
-    let window = create_window();
+    let Some(output) = parse_args(std::env::args().skip(1)) else {
+        eprintln!("{}", USAGE);
+        std::process::exit(2);
+    };
+    let mut window = match create_window(WidgetOptions::default()) {
+        Ok(window) => window,
+        Err(err) => {
+            eprintln!("Failed to start the canvas: {:#}", err);
+            std::process::exit(1);
+        }
+    };
+    window.set_output_path(output);
     window.run_window_loop();
 }
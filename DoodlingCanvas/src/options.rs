@@ -0,0 +1,112 @@
+use image::RgbaImage;
+
+use crate::{import::ImageFit, layers::Background, utils};
+
+/// How a canvas widget is set up by `create_window`
+pub struct WidgetOptions {
+    pub width: u32,
+    pub height: u32,
+    // Drawing is disabled, the doodle can still be panned and zoomed
+    pub read_only: bool,
+    // Loaded into the bottom layer once the canvas is ready. sRGB with straight alpha
+    pub image: Option<RgbaImage>,
+    // How the image is placed when its size isn't the canvas size
+    pub image_fit: ImageFit,
+    // Under the layers, "transparent" shows a checkerboard and exports with alpha
+    pub background: Background,
+    #[cfg(target_arch = "wasm32")]
+    pub canvas: web_sys::HtmlCanvasElement,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl Default for WidgetOptions {
+    fn default() -> Self {
+        Self {
+            width: utils::DEFAULT_CANVAS_WIDTH,
+            height: utils::DEFAULT_CANVAS_HEIGHT,
+            read_only: false,
+            image: None,
+            image_fit: ImageFit::default(),
+            background: Background::default(),
+        }
+    }
+}
+
+// Encoded PNG, JPEG, WebP... given as base64, optionally in a data URL
+#[cfg(target_arch = "wasm32")]
+fn decode_base64_image(data: &str) -> anyhow::Result<RgbaImage> {
+    use anyhow::Context;
+    use base64::{engine::general_purpose::STANDARD, Engine};
+    let data = data.split_once(";base64,").map_or(data, |(_, data)| data);
+    crate::import::decode(
+        &STANDARD
+            .decode(data.trim())
+            .context("The initial image isn't valid base64")?,
+    )
+}
+
+#[cfg(target_arch = "wasm32")]
+impl WidgetOptions {
+    /// Reads `{ canvas, width, height, read_only, image, image_fit, background }` where every field is optional:
+    /// `canvas` is an element id or an `HTMLCanvasElement` (`"canvas"` by default),
+    /// `image` a `Uint8Array` or a base64 string, `image_fit` an `ImageFit`
+    /// and `background` `"transparent"` or a `"#rrggbb"` color
+    pub fn from_js(options: &wasm_bindgen::JsValue) -> anyhow::Result<Self> {
+        use anyhow::{anyhow, bail};
+        use wasm_bindgen::{JsCast, JsValue};
+        let get = |key: &str| {
+            if options.is_object() {
+                js_sys::Reflect::get(options, &JsValue::from_str(key)).unwrap_or(JsValue::UNDEFINED)
+            } else {
+                JsValue::UNDEFINED
+            }
+        };
+        let size = |key: &str, default: u32| get(key).as_f64().map_or(default, |size| size as u32);
+
+        let canvas = get("canvas");
+        let canvas = if canvas.is_undefined() || canvas.is_null() {
+            JsValue::from_str("canvas")
+        } else {
+            canvas
+        };
+        let canvas = match canvas.as_string() {
+            Some(id) => web_sys::window()
+                .and_then(|window| window.document())
+                .and_then(|document| document.get_element_by_id(&id))
+                .and_then(|element| element.dyn_into().ok())
+                .ok_or_else(|| anyhow!("The page has no <canvas id=\"{}\"> element", id))?,
+            None => canvas
+                .dyn_into()
+                .map_err(|_| anyhow!("canvas must be an element id or a canvas element"))?,
+        };
+
+        let image = get("image");
+        let image = if image.is_undefined() || image.is_null() {
+            None
+        } else if let Some(data) = image.as_string() {
+            Some(decode_base64_image(&data)?)
+        } else if let Some(bytes) = image.dyn_ref::<js_sys::Uint8Array>() {
+            Some(crate::import::decode(&bytes.to_vec())?)
+        } else {
+            bail!("image must be a Uint8Array or a base64 string");
+        };
+
+        let background = match get("background").as_string() {
+            Some(background) => background.parse().map_err(anyhow::Error::msg)?,
+            None => Background::default(),
+        };
+
+        Ok(Self {
+            width: size("width", utils::DEFAULT_CANVAS_WIDTH),
+            height: size("height", utils::DEFAULT_CANVAS_HEIGHT),
+            read_only: get("read_only").is_truthy(),
+            image,
+            image_fit: get("image_fit")
+                .as_f64()
+                .and_then(|fit| ImageFit::ALL.get(fit as usize).copied())
+                .unwrap_or_default(),
+            background,
+            canvas,
+        })
+    }
+}
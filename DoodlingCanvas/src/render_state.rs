@@ -1,10 +1,19 @@
 use core::fmt;
+use std::collections::VecDeque;
 use std::fmt::Formatter;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 
-use crate::utils::{self, WINDOW_HEIGHT, WINDOW_WIDTH};
+use crate::brush::{DabBatch, DabInstance};
+use crate::color;
+use crate::document::LayerSettings;
+use crate::layers::{Background, BlendMode, Layer, LayerCommand};
+use crate::shapes::Shape;
+use crate::stroke::{Dab, Stroke};
+use crate::viewport::{ViewTransform, Viewport};
+use anyhow::{anyhow, bail, Context};
 use image::GenericImage;
-use log::error;
 use wgpu::util::DeviceExt;
 use wgpu::{
     BindGroupLayout, CommandEncoder, Device, PipelineCompilationOptions, RenderPipeline,
@@ -32,91 +41,230 @@ impl Vertex {
     }
 }
 
-pub struct State {
-    pub surface: wgpu::Surface<'static>,
-    pub device: wgpu::Device,
+/// The device of the first graphics backend that works, picked before there is a window to draw into
+pub struct GpuContext {
+    instance: wgpu::Instance,
+    adapter: wgpu::Adapter,
+    device: wgpu::Device,
     queue: wgpu::Queue,
-    pub config: wgpu::SurfaceConfiguration,
-    pub size: winit::dpi::PhysicalSize<u32>,
-    pub window: Arc<Window>,
-    display_render_pipeline: wgpu::RenderPipeline,
-    canvas_texture: wgpu::Texture,
-    canvas_render_pipeline: wgpu::RenderPipeline,
-    canvas_bind_group: wgpu::BindGroup,
-    offset_bind_group: wgpu::BindGroup,
-    drawing_offset_buffer: wgpu::Buffer,
+    // Always set on the web, where a WebGL adapter can only be found for a surface
+    surface: Option<wgpu::Surface<'static>>,
 }
 
-pub type RenderCommands = CommandEncoder;
-impl State {
-    const CLEAR_COLOR: wgpu::Color = wgpu::Color {
-        r: 0.2,
-        g: 0.2,
-        b: 0.2,
-        a: 1.0,
-    };
-    // Creating some of the wgpu types requires async code
-    pub async fn new(window: Arc<Window>) -> Self {
-        let size = PhysicalSize {
-            width: WINDOW_WIDTH,
-            height: WINDOW_HEIGHT,
-        };
+impl GpuContext {
+    // Tried in order, with whether to ask for a software adapter
+    #[cfg(target_arch = "wasm32")]
+    const BACKENDS: [(wgpu::Backends, bool); 2] = [
+        (wgpu::Backends::BROWSER_WEBGPU, false),
+        (wgpu::Backends::GL, false),
+    ];
+    // Vulkan, Metal or DX12 first, then OpenGL, then whatever software adapter the system has
+    #[cfg(not(target_arch = "wasm32"))]
+    const BACKENDS: [(wgpu::Backends, bool); 3] = [
+        (wgpu::Backends::PRIMARY, false),
+        (wgpu::Backends::GL, false),
+        (wgpu::Backends::all(), true),
+    ];
+
+    // Native surfaces are created once the window exists, in `State::new`
+    #[cfg(not(target_arch = "wasm32"))]
+    pub async fn negotiate() -> anyhow::Result<Self> {
+        let mut failures = Vec::new();
+        for (backends, software) in Self::BACKENDS {
+            let instance = wgpu::Instance::new(wgpu::InstanceDescriptor {
+                backends,
+                ..Default::default()
+            });
+            match Self::with_adapter(instance, None, software).await {
+                Ok(gpu) => return Ok(gpu),
+                Err(err) => failures.push(format!("{:?}: {:#}", backends, err)),
+            }
+        }
+        bail!("No usable graphics adapter ({})", failures.join("; "))
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    pub async fn negotiate(canvas: &web_sys::HtmlCanvasElement) -> anyhow::Result<Self> {
+        let mut failures = Vec::new();
+        for (backends, software) in Self::BACKENDS {
+            match Self::with_canvas(backends, canvas, software).await {
+                Ok(gpu) => return Ok(gpu),
+                Err(err) => failures.push(format!("{:?}: {:#}", backends, err)),
+            }
+        }
+        bail!(
+            "Neither WebGPU nor WebGL2 is available ({})",
+            failures.join("; ")
+        )
+    }
 
-        // The instance is a handle to our GPU
-        // Backends::all => Vulkan + Metal + DX12 + Browser WebGPU
+    // A canvas only ever hands out one kind of context. WebGPU adapters don't need a surface, so the
+    // canvas is only asked for a WebGPU context once there is an adapter, keeping WebGL2 possible
+    #[cfg(target_arch = "wasm32")]
+    async fn with_canvas(
+        backends: wgpu::Backends,
+        canvas: &web_sys::HtmlCanvasElement,
+        software: bool,
+    ) -> anyhow::Result<Self> {
         let instance = wgpu::Instance::new(wgpu::InstanceDescriptor {
-            backends: wgpu::Backends::GL,
-            dx12_shader_compiler: Default::default(),
-            flags: Default::default(),
-            gles_minor_version: Default::default(),
+            backends,
+            ..Default::default()
         });
-        // # Safety
-        //
-        // The surface needs to live as long as the window that created it.
-        // State owns the window so this should be safe.
-        let surface = {
-            match instance.create_surface(window.clone()) {
-                Ok(surface) => surface,
-                Err(err) => {
-                    error!("Failed to create surface: {:?}", err);
-                    unreachable!()
-                }
-            }
+        let create_surface = |instance: &wgpu::Instance| {
+            instance
+                .create_surface(wgpu::SurfaceTarget::Canvas(canvas.clone()))
+                .context("Failed to create a surface for the canvas")
         };
+        if backends == wgpu::Backends::BROWSER_WEBGPU {
+            let mut gpu = Self::with_adapter(instance, None, software).await?;
+            gpu.surface = Some(create_surface(&gpu.instance)?);
+            Ok(gpu)
+        } else {
+            let surface = create_surface(&instance)?;
+            Self::with_adapter(instance, Some(surface), software).await
+        }
+    }
 
+    async fn with_adapter(
+        instance: wgpu::Instance,
+        surface: Option<wgpu::Surface<'static>>,
+        software: bool,
+    ) -> anyhow::Result<Self> {
         let adapter = instance
             .request_adapter(&wgpu::RequestAdapterOptions {
-                compatible_surface: Some(&surface),
+                compatible_surface: surface.as_ref(),
                 power_preference: wgpu::PowerPreference::None,
-                force_fallback_adapter: false,
+                force_fallback_adapter: software,
             })
             .await
-            .unwrap();
-
+            .ok_or_else(|| anyhow!("No adapter found"))?;
+        log::info!("Using adapter {:?}", adapter.get_info());
         let (device, queue) = adapter
             .request_device(
                 &wgpu::DeviceDescriptor {
                     required_features: wgpu::Features::empty(),
-                    // WebGL doesn't support all of wgpu's features, so if
-                    // we're building for the web we'll have to disable some.
-                    required_limits: if cfg!(target_arch = "wasm32") {
-                        wgpu::Limits::downlevel_webgl2_defaults()
-                    } else {
-                        wgpu::Limits::default()
-                    },
+                    // Nothing beyond what WebGL2 supports is used, but larger canvases need larger textures
+                    required_limits: wgpu::Limits::downlevel_webgl2_defaults()
+                        .using_resolution(adapter.limits()),
                     label: None,
                 },
                 None, // Trace path
             )
             .await
-            .unwrap();
+            .context("Failed to create the device")?;
+        Ok(Self {
+            instance,
+            adapter,
+            device,
+            queue,
+            surface,
+        })
+    }
+}
+
+pub struct State {
+    // None when rendering headless, the canvas then only lives in the layer textures
+    pub surface: Option<wgpu::Surface<'static>>,
+    pub device: wgpu::Device,
+    queue: wgpu::Queue,
+    pub config: wgpu::SurfaceConfiguration,
+    pub size: winit::dpi::PhysicalSize<u32>,
+    pub window: Option<Arc<Window>>,
+    display_render_pipelines: [wgpu::RenderPipeline; 4],
+    // Fills the canvas area of the surface with the background before the layers are drawn
+    background_pipeline: wgpu::RenderPipeline,
+    background: Background,
+    flatten_render_pipelines: [wgpu::RenderPipeline; 4],
+    canvas_size: wgpu::Extent3d,
+    canvas_sampler: wgpu::Sampler,
+    layer_bind_group_layout: wgpu::BindGroupLayout,
+    // Ordered bottom to top
+    layers: Vec<Layer>,
+    active_layer: usize,
+    canvas_render_pipeline: wgpu::RenderPipeline,
+    brush_bind_group: wgpu::BindGroup,
+    shape_render_pipeline: wgpu::RenderPipeline,
+    shape_preview_pipeline: wgpu::RenderPipeline,
+    shape_uniform_buffer: wgpu::Buffer,
+    shape_bind_group: wgpu::BindGroup,
+    // The preview has its own uniform so it can't be overwritten by a commit in the same frame
+    shape_preview_uniform_buffer: wgpu::Buffer,
+    shape_preview_bind_group: wgpu::BindGroup,
+    // Vertex buffer and vertex count of the shape being dragged out
+    shape_preview: Option<(wgpu::Buffer, u32)>,
+    // Layer index and its contents before each undoable operation, oldest first
+    undo_stack: VecDeque<(usize, wgpu::Texture, UndoKind)>,
+    view: ViewTransform,
+    view_uniform_buffer: wgpu::Buffer,
+    view_bind_group: wgpu::BindGroup,
+    identity_view_bind_group: wgpu::BindGroup,
+    // Brush dabs that haven't been drawn into their layer yet
+    dabs: DabBatch,
+    // Set when the canvas changed since the last frame was presented
+    dirty: bool,
+    // Set by the device lost callback, the state has to be rebuilt on a new device
+    device_lost: Arc<AtomicBool>,
+}
+
+/// What an undo step was recorded for, undoing a stroke also drops it from the recorded strokes
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UndoKind {
+    Stroke,
+    // Fills, shapes and imported images, which aren't recorded as strokes
+    Paint,
+}
+
+/// Copy of the canvas in system memory, a new state is filled from it when the device is lost
+#[derive(Clone)]
+pub struct CanvasSnapshot {
+    // Bottom to top, with the pixels as stored (premultiplied)
+    layers: Vec<(image::RgbaImage, LayerSettings)>,
+    active_layer: usize,
+    view: ViewTransform,
+    background: Background,
+}
+
+pub type RenderCommands = CommandEncoder;
+impl State {
+    const MAX_UNDO_STEPS: usize = 20;
+    // The device has already been picked by `GpuContext::negotiate`, on the web along with its surface
+    pub fn new(
+        window: Arc<Window>,
+        canvas_size: PhysicalSize<u32>,
+        gpu: GpuContext,
+    ) -> anyhow::Result<Self> {
+        // The surface covers the whole window, which can be larger than the canvas on HiDPI screens
+        let size = window.inner_size();
+        let size = PhysicalSize::new(size.width.max(1), size.height.max(1));
+        let GpuContext {
+            instance,
+            adapter,
+            device,
+            queue,
+            surface,
+        } = gpu;
+        // The surface needs to live as long as the window that created it.
+        // State owns the window so this should be safe.
+        let surface = match surface {
+            Some(surface) => surface,
+            None => instance
+                .create_surface(window.clone())
+                .context("Failed to create a surface for the window")?,
+        };
+        if !adapter.is_surface_supported(&surface) {
+            bail!(
+                "The {} adapter can't present to this window",
+                adapter.get_info().name
+            );
+        }
         let surface_caps = surface.get_capabilities(&adapter);
-        let surface_format = surface_caps
-            .formats
-            .iter()
-            .copied()
-            .find(|f| f.is_srgb())
-            .unwrap_or(surface_caps.formats[0]);
+        let (surface_format, view_formats) = Self::choose_surface_format(
+            &surface_caps.formats,
+            adapter
+                .get_downlevel_capabilities()
+                .flags
+                .contains(wgpu::DownlevelFlags::SURFACE_VIEW_FORMATS),
+        );
 
         let config = wgpu::SurfaceConfiguration {
             usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
@@ -125,35 +273,117 @@ impl State {
             height: size.height,
             present_mode: surface_caps.present_modes[0],
             alpha_mode: surface_caps.alpha_modes[0],
-            view_formats: vec![],
+            view_formats,
             desired_maximum_frame_latency: 1,
         };
         surface.configure(&device, &config);
+        Ok(Self::with_device(
+            device,
+            queue,
+            config,
+            Some(surface),
+            Some(window),
+            canvas_size,
+        ))
+    }
 
+    // Renders into the layer textures only, without a window. The adapter can be a software one,
+    // which makes it usable from scripts and tests on machines without a display
+    #[cfg(not(target_arch = "wasm32"))]
+    pub async fn new_headless(canvas_size: PhysicalSize<u32>) -> anyhow::Result<Self> {
+        let gpu = GpuContext::negotiate().await?;
+        // Nothing is presented, the display pipelines draw in the layer format
+        let config = wgpu::SurfaceConfiguration {
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
+            format: Layer::FORMAT,
+            width: canvas_size.width,
+            height: canvas_size.height,
+            present_mode: wgpu::PresentMode::Fifo,
+            alpha_mode: wgpu::CompositeAlphaMode::Opaque,
+            view_formats: vec![],
+            desired_maximum_frame_latency: 1,
+        };
+        Ok(Self::with_device(
+            gpu.device,
+            gpu.queue,
+            config,
+            None,
+            None,
+            canvas_size,
+        ))
+    }
+
+    // The shaders output linear colors, so the surface has to encode them to sRGB like the layers do.
+    // 8-bit RGBA or BGRA sRGB formats are preferred, otherwise the first format is viewed as sRGB
+    // when the backend can. The second value is the view format, when it differs from the surface's
+    fn choose_surface_format(
+        formats: &[TextureFormat],
+        srgb_views: bool,
+    ) -> (TextureFormat, Vec<TextureFormat>) {
+        const PREFERRED: [TextureFormat; 2] =
+            [TextureFormat::Rgba8UnormSrgb, TextureFormat::Bgra8UnormSrgb];
+        if let Some(format) = PREFERRED
+            .into_iter()
+            .find(|format| formats.contains(format))
+            .or_else(|| formats.iter().copied().find(|format| format.is_srgb()))
+        {
+            return (format, vec![]);
+        }
+        let format = formats[0];
+        if srgb_views && format.add_srgb_suffix() != format {
+            return (format, vec![format.add_srgb_suffix()]);
+        }
+        log::warn!(
+            "No sRGB surface format among {:?}, the canvas will look darker than it is",
+            formats
+        );
+        (format, vec![])
+    }
+
+    // The format the display pipelines render to the surface in
+    fn display_format(config: &wgpu::SurfaceConfiguration) -> TextureFormat {
+        config
+            .view_formats
+            .first()
+            .copied()
+            .unwrap_or(config.format)
+    }
+
+    fn with_device(
+        device: wgpu::Device,
+        queue: wgpu::Queue,
+        config: wgpu::SurfaceConfiguration,
+        surface: Option<wgpu::Surface<'static>>,
+        window: Option<Arc<Window>>,
+        canvas_size: PhysicalSize<u32>,
+    ) -> Self {
+        let size = PhysicalSize::new(config.width, config.height);
+        let display_format = Self::display_format(&config);
+        let device_lost = Arc::new(AtomicBool::new(false));
+        let lost = device_lost.clone();
+        device.set_device_lost_callback(move |reason, message| {
+            // Dropping the device, e.g. when the state is replaced, reports a loss as well
+            if !matches!(
+                reason,
+                wgpu::DeviceLostReason::Dropped | wgpu::DeviceLostReason::ReplacedCallback
+            ) {
+                log::error!("Graphics device lost ({:?}): {}", reason, message);
+                lost.store(true, Ordering::Relaxed);
+            }
+        });
+        // The default handler panics, which would take the doodle down with it
+        device.on_uncaptured_error(Box::new(|err| log::error!("Graphics error: {}", err)));
         let render_shader =
             device.create_shader_module(wgpu::include_wgsl!("shaders/render_shader.wgsl"));
         let canvas_shader =
             device.create_shader_module(wgpu::include_wgsl!("shaders/canvas_shader.wgsl"));
-        //The canvas texture
-        let texture_desc = wgpu::TextureDescriptor {
-            size: wgpu::Extent3d {
-                width: utils::WINDOW_WIDTH,
-                height: utils::WINDOW_HEIGHT,
-                depth_or_array_layers: 1,
-            },
-            mip_level_count: 1,
-            sample_count: 1,
-            dimension: wgpu::TextureDimension::D2,
-            format: wgpu::TextureFormat::Rgba8UnormSrgb,
-            usage: wgpu::TextureUsages::COPY_SRC
-                | wgpu::TextureUsages::RENDER_ATTACHMENT
-                | wgpu::TextureUsages::TEXTURE_BINDING
-                | wgpu::TextureUsages::COPY_DST,
-            label: None,
-            view_formats: &[wgpu::TextureFormat::Rgba8UnormSrgb],
+        let shape_shader =
+            device.create_shader_module(wgpu::include_wgsl!("shaders/shape_shader.wgsl"));
+        let canvas_size = wgpu::Extent3d {
+            width: canvas_size.width,
+            height: canvas_size.height,
+            depth_or_array_layers: 1,
         };
-        let texture = device.create_texture(&texture_desc);
-        let canvas_view = texture.create_view(&wgpu::TextureViewDescriptor::default());
         let canvas_sampler = device.create_sampler(&wgpu::SamplerDescriptor {
             address_mode_u: wgpu::AddressMode::ClampToEdge,
             address_mode_v: wgpu::AddressMode::ClampToEdge,
@@ -163,55 +393,24 @@ impl State {
             mipmap_filter: wgpu::FilterMode::Nearest,
             ..Default::default()
         });
-        let texture_bind_group_layout =
-            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
-                entries: &[
-                    wgpu::BindGroupLayoutEntry {
-                        binding: 0,
-                        visibility: wgpu::ShaderStages::FRAGMENT,
-                        ty: wgpu::BindingType::Texture {
-                            multisampled: false,
-                            view_dimension: wgpu::TextureViewDimension::D2,
-                            sample_type: wgpu::TextureSampleType::Float { filterable: true },
-                        },
-                        count: None,
-                    },
-                    wgpu::BindGroupLayoutEntry {
-                        binding: 1,
-                        visibility: wgpu::ShaderStages::FRAGMENT,
-                        // This should match the filterable field of the
-                        // corresponding Texture entry above.
-                        ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
-                        count: None,
-                    },
-                ],
-                label: Some("texture_bind_group_layout"),
-            });
-        let canvas_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-            layout: &texture_bind_group_layout,
-            entries: &[
-                wgpu::BindGroupEntry {
-                    binding: 0,
-                    resource: wgpu::BindingResource::TextureView(&canvas_view),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 1,
-                    resource: wgpu::BindingResource::Sampler(&canvas_sampler),
-                },
-            ],
-            label: Some("diffuse_bind_group"),
-        });
-        let offset_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-            label: Some("Offset Buffer"),
-            contents: bytemuck::cast_slice(&[0.0f32, 0.0f32, 0.0f32, 0.0f32]),
-            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        let layer_bind_group_layout = Layer::bind_group_layout(&device);
+        let base_layer = Layer::new(
+            &device,
+            &layer_bind_group_layout,
+            &canvas_sampler,
+            canvas_size,
+        );
+        let brush_uniform_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Brush Uniform Buffer"),
+            contents: bytemuck::cast_slice(&Self::layer_transform(canvas_size)),
+            usage: wgpu::BufferUsages::UNIFORM,
         });
         let offset_bind_group_layout =
             device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
-                label: Some("Offset Buffer Layout"),
+                label: Some("Uniform Buffer Layout"),
                 entries: &[wgpu::BindGroupLayoutEntry {
                     binding: 0,
-                    visibility: wgpu::ShaderStages::VERTEX,
+                    visibility: wgpu::ShaderStages::VERTEX_FRAGMENT,
                     ty: wgpu::BindingType::Buffer {
                         ty: wgpu::BufferBindingType::Uniform,
                         has_dynamic_offset: false,
@@ -220,48 +419,319 @@ impl State {
                     count: None,
                 }],
             });
-        let offset_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-            label: Some("Offset Bind Group"),
+        let brush_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("Brush Bind Group"),
             layout: &offset_bind_group_layout,
             entries: &[wgpu::BindGroupEntry {
                 binding: 0,
-                resource: offset_buffer.as_entire_binding(),
+                resource: brush_uniform_buffer.as_entire_binding(),
             }],
         });
-        let render_pipeline: RenderPipeline = Self::create_pipeline(
+        let create_shape_uniform = |label| {
+            let buffer = device.create_buffer(&wgpu::BufferDescriptor {
+                label: Some(label),
+                size: std::mem::size_of::<[f32; 8]>() as wgpu::BufferAddress,
+                usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+                mapped_at_creation: false,
+            });
+            let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+                label: Some(label),
+                layout: &offset_bind_group_layout,
+                entries: &[wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: buffer.as_entire_binding(),
+                }],
+            });
+            (buffer, bind_group)
+        };
+        let (shape_uniform_buffer, shape_bind_group) = create_shape_uniform("Shape Uniform");
+        let (shape_preview_uniform_buffer, shape_preview_bind_group) =
+            create_shape_uniform("Shape Preview Uniform");
+        // Where the layers are placed on the surface, the flattened export always covers the whole texture
+        let (view_uniform_buffer, view_bind_group) = create_shape_uniform("View Uniform");
+        let (identity_view_uniform_buffer, identity_view_bind_group) =
+            create_shape_uniform("Identity View Uniform");
+        queue.write_buffer(
+            &identity_view_uniform_buffer,
+            0,
+            bytemuck::cast_slice(&[1.0f32, 1.0, 0.0, 0.0]),
+        );
+        // One compositing pipeline per blend mode, for both the surface and the flattened export
+        let display_render_pipelines = BlendMode::ALL.map(|mode| {
+            Self::create_pipeline(
+                &device,
+                display_format,
+                &render_shader,
+                "fs_main",
+                &[&layer_bind_group_layout, &offset_bind_group_layout],
+                &[],
+                mode.blend_state(),
+                Some(wgpu::Face::Back),
+            )
+        });
+        // Shares the layout of the layers' pipelines but only reads the view uniform
+        let background_pipeline = Self::create_pipeline(
             &device,
-            config.format,
-            render_shader,
-            &[&texture_bind_group_layout],
+            display_format,
+            &render_shader,
+            "fs_background",
+            &[&layer_bind_group_layout, &offset_bind_group_layout],
             &[],
+            wgpu::BlendState::REPLACE,
+            Some(wgpu::Face::Back),
         );
+        let flatten_render_pipelines = BlendMode::ALL.map(|mode| {
+            Self::create_pipeline(
+                &device,
+                Layer::FORMAT,
+                &render_shader,
+                "fs_main",
+                &[&layer_bind_group_layout, &offset_bind_group_layout],
+                &[],
+                mode.blend_state(),
+                Some(wgpu::Face::Back),
+            )
+        });
         let canvas_render_pipeline: RenderPipeline = Self::create_pipeline(
             &device,
-            wgpu::TextureFormat::Rgba8UnormSrgb,
-            canvas_shader,
+            Layer::FORMAT,
+            &canvas_shader,
+            "fs_main",
+            &[&offset_bind_group_layout],
+            &[Vertex::desc(), DabInstance::desc()],
+            wgpu::BlendState::PREMULTIPLIED_ALPHA_BLENDING,
+            // The quad is wound in canvas space, where y points down
+            None,
+        );
+        // Shapes can be dragged in any direction so their winding isn't fixed
+        let shape_render_pipeline = Self::create_pipeline(
+            &device,
+            Layer::FORMAT,
+            &shape_shader,
+            "fs_main",
             &[&offset_bind_group_layout],
             &[Vertex::desc()],
+            wgpu::BlendState::PREMULTIPLIED_ALPHA_BLENDING,
+            None,
         );
-        Self {
+        let shape_preview_pipeline = Self::create_pipeline(
+            &device,
+            display_format,
+            &shape_shader,
+            "fs_main",
+            &[&offset_bind_group_layout],
+            &[Vertex::desc()],
+            wgpu::BlendState::PREMULTIPLIED_ALPHA_BLENDING,
+            None,
+        );
+        let dabs = DabBatch::new(&device);
+        let mut state = Self {
             window,
             surface,
             device,
             queue,
             config,
             size,
-            display_render_pipeline: render_pipeline,
-            canvas_texture: texture,
+            display_render_pipelines,
+            background_pipeline,
+            background: Background::default(),
+            flatten_render_pipelines,
+            canvas_size,
+            canvas_sampler,
+            layer_bind_group_layout,
+            layers: vec![base_layer],
+            active_layer: 0,
             canvas_render_pipeline,
-            canvas_bind_group,
-            offset_bind_group,
-            drawing_offset_buffer: offset_buffer,
+            brush_bind_group,
+            shape_render_pipeline,
+            shape_preview_pipeline,
+            shape_uniform_buffer,
+            shape_bind_group,
+            shape_preview_uniform_buffer,
+            shape_preview_bind_group,
+            shape_preview: None,
+            undo_stack: VecDeque::new(),
+            view: ViewTransform::default(),
+            view_uniform_buffer,
+            view_bind_group,
+            identity_view_bind_group,
+            dabs,
+            dirty: false,
+            device_lost,
+        };
+        state.update_view();
+        let mut commands = state.begin_render();
+        state.clear_screen(&mut commands);
+        state.end_render(commands);
+        state
+    }
+
+    // Frames are only drawn when something changed, this asks the window for one
+    pub fn mark_dirty(&mut self) {
+        if self.dirty {
+            return;
         }
+        self.dirty = true;
+        if let Some(window) = &self.window {
+            window.request_redraw();
+        }
+    }
+    pub fn is_dirty(&self) -> bool {
+        self.dirty
     }
 
-    pub fn window(&self) -> &Window {
-        &self.window
+    pub fn window(&self) -> Option<&Window> {
+        self.window.as_deref()
+    }
+    pub fn canvas_size(&self) -> PhysicalSize<u32> {
+        PhysicalSize::new(self.canvas_size.width, self.canvas_size.height)
+    }
+    pub fn viewport(&self) -> Viewport {
+        Viewport::with_view(self.size, self.canvas_size(), self.view)
+    }
+    pub fn view(&self) -> ViewTransform {
+        self.view
+    }
+    pub fn set_view(&mut self, view: ViewTransform) {
+        self.view = view;
+        self.update_view();
+    }
+    pub fn background(&self) -> Background {
+        self.background
+    }
+    pub fn set_background(&mut self, background: Background) {
+        self.background = background;
+        self.update_view();
+    }
+    pub fn is_device_lost(&self) -> bool {
+        self.device_lost.load(Ordering::Relaxed)
+    }
+    // Lost and outdated surfaces only need to be configured again
+    pub fn reconfigure_surface(&mut self) {
+        if let Some(surface) = &self.surface {
+            surface.configure(&self.device, &self.config);
+        }
+    }
+    // Reconfigures the surface for the new window size, in physical pixels
+    pub fn resize(&mut self, new_size: PhysicalSize<u32>) {
+        if new_size.width == 0 || new_size.height == 0 {
+            return;
+        }
+        self.size = new_size;
+        self.config.width = new_size.width;
+        self.config.height = new_size.height;
+        if let Some(surface) = &self.surface {
+            surface.configure(&self.device, &self.config);
+        }
+        self.update_view();
+    }
+    fn update_view(&mut self) {
+        self.mark_dirty();
+        let viewport = self.viewport();
+        let mut view_uniform = [0.0f32; 8];
+        view_uniform[..4].copy_from_slice(&viewport.quad_transform());
+        view_uniform[4..].copy_from_slice(&self.background.to_linear());
+        self.queue.write_buffer(
+            &self.view_uniform_buffer,
+            0,
+            bytemuck::cast_slice(&view_uniform),
+        );
+        // The preview is in canvas pixels, so it has to follow the canvas around
+        self.queue.write_buffer(
+            &self.shape_preview_uniform_buffer,
+            0,
+            bytemuck::cast_slice(&viewport.canvas_to_ndc_transform()),
+        );
+    }
+    pub fn layer_count(&self) -> usize {
+        self.layers.len()
+    }
+    pub fn active_layer(&self) -> usize {
+        self.active_layer
+    }
+    // Bottom to top, as stored in stroke documents
+    pub fn layer_settings(&self) -> Vec<LayerSettings> {
+        self.layers
+            .iter()
+            .map(|layer| LayerSettings {
+                opacity: layer.opacity,
+                visible: layer.visible,
+                blend_mode: layer.blend_mode,
+            })
+            .collect()
+    }
+    // Out of range indices are ignored, as are attempts to remove the last remaining layer.
+    // Returns whether the layer stack or the way it's composited changed, selecting a layer doesn't count
+    pub fn apply_layer_command(&mut self, command: LayerCommand) -> bool {
+        self.flush_dabs();
+        let count = self.layers.len();
+        let changed = match command {
+            LayerCommand::Add => {
+                // New layers start transparent and are placed above the active one
+                let layer = Layer::new(
+                    &self.device,
+                    &self.layer_bind_group_layout,
+                    &self.canvas_sampler,
+                    self.canvas_size,
+                );
+                self.active_layer += 1;
+                self.layers.insert(self.active_layer, layer);
+                true
+            }
+            LayerCommand::Remove(index) if index < count && count > 1 => {
+                self.layers.remove(index);
+                if self.active_layer > index || self.active_layer == self.layers.len() {
+                    self.active_layer -= 1;
+                }
+                true
+            }
+            LayerCommand::Move { from, to } if from < count && to < count => {
+                let layer = self.layers.remove(from);
+                self.layers.insert(to, layer);
+                // Keep the same layer selected after the move
+                if self.active_layer == from {
+                    self.active_layer = to;
+                } else if from < self.active_layer && to >= self.active_layer {
+                    self.active_layer -= 1;
+                } else if from > self.active_layer && to <= self.active_layer {
+                    self.active_layer += 1;
+                }
+                from != to
+            }
+            LayerCommand::Select(index) if index < count => {
+                self.active_layer = index;
+                false
+            }
+            LayerCommand::SetOpacity(index, opacity) if index < count => {
+                let previous = self.layers[index].opacity;
+                self.layers[index].set_opacity(&self.queue, opacity);
+                self.layers[index].opacity != previous
+            }
+            LayerCommand::SetVisible(index, visible) if index < count => {
+                std::mem::replace(&mut self.layers[index].visible, visible) != visible
+            }
+            LayerCommand::SetBlendMode(index, blend_mode) if index < count => {
+                std::mem::replace(&mut self.layers[index].blend_mode, blend_mode) != blend_mode
+            }
+            _ => {
+                log::warn!("Ignoring invalid layer command {:?}", command);
+                false
+            }
+        };
+        if changed {
+            // The undo history refers to layers by index, which changes when the stack is rearranged
+            if matches!(
+                command,
+                LayerCommand::Add | LayerCommand::Remove(_) | LayerCommand::Move { .. }
+            ) {
+                self.undo_stack.clear();
+            }
+            self.mark_dirty();
+        }
+        changed
     }
     pub fn begin_render(&mut self) -> RenderCommands {
+        self.flush_dabs();
         self.device
             .create_command_encoder(&wgpu::CommandEncoderDescriptor {
                 label: Some("Render Encoder"),
@@ -269,86 +739,171 @@ impl State {
     }
     pub fn end_render(&mut self, commands: RenderCommands) {
         self.queue.submit(std::iter::once(commands.finish()));
+        self.mark_dirty();
     }
 
     pub fn clear_screen(&mut self, commands: &mut RenderCommands) {
-        let color_attachment_operation = wgpu::Operations {
-            load: wgpu::LoadOp::Clear(Self::CLEAR_COLOR),
-            store: wgpu::StoreOp::Store,
-        };
-        {
-            let view = self
-                .canvas_texture
-                .create_view(&wgpu::TextureViewDescriptor::default());
-            let mut render_pass = commands.begin_render_pass(&wgpu::RenderPassDescriptor {
+        // Layers are cleared to transparent, the background is drawn beneath them when compositing
+        for layer in &self.layers {
+            let view = layer.view();
+            let _render_pass = commands.begin_render_pass(&wgpu::RenderPassDescriptor {
                 label: Some("Clear Canvas Render Pass"),
                 color_attachments: &[Some(wgpu::RenderPassColorAttachment {
                     view: &view,
                     resolve_target: None,
-                    ops: color_attachment_operation,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(wgpu::Color::TRANSPARENT),
+                        store: wgpu::StoreOp::Store,
+                    },
                 })],
                 depth_stencil_attachment: None,
                 timestamp_writes: None,
                 occlusion_query_set: None,
             });
+        }
+    }
 
-            render_pass.set_pipeline(&self.canvas_render_pipeline);
+    // Queues a single brush dab, centered on its position, for the active layer.
+    // The dab reaches the layer texture when the batch is flushed
+    pub fn draw_dab(&mut self, dab: &Dab) {
+        if self
+            .dabs
+            .layer()
+            .is_some_and(|layer| layer != self.active_layer)
+        {
+            self.flush_dabs();
         }
+        self.dabs.push(self.active_layer, dab);
+        self.mark_dirty();
     }
 
-    pub fn draw_buffer(
-        &mut self,
-        commands: &mut RenderCommands,
-        buffer: &wgpu::Buffer,
-        offset: [f32; 2],
-    ) {
-        let color_attachment_operation = wgpu::Operations {
-            load: wgpu::LoadOp::Load,
-            store: wgpu::StoreOp::Store,
+    // Draws the queued dabs with one instanced draw call. Called before anything that uses the layers
+    fn flush_dabs(&mut self) {
+        let Some(layer) = self.dabs.layer() else {
+            return;
         };
-        {
-            self.queue.write_buffer(
-                &self.drawing_offset_buffer,
-                0,
-                bytemuck::cast_slice(&offset),
-            );
-            let view = self
-                .canvas_texture
-                .create_view(&wgpu::TextureViewDescriptor::default());
-            let mut render_pass = commands.begin_render_pass(&wgpu::RenderPassDescriptor {
-                label: Some("Draw Buffer Render Pass"),
-                color_attachments: &[Some(wgpu::RenderPassColorAttachment {
-                    view: &view,
-                    resolve_target: None,
-                    ops: color_attachment_operation,
-                })],
-                depth_stencil_attachment: None,
-                timestamp_writes: None,
-                occlusion_query_set: None,
+        let mut encoder = self
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("Brush Encoder"),
             });
+        let view = self.layers[layer].view();
+        self.dabs.draw(
+            &self.device,
+            &self.queue,
+            &mut encoder,
+            &self.canvas_render_pipeline,
+            &self.brush_bind_group,
+            &view,
+        );
+        self.queue.submit(Some(encoder.finish()));
+    }
 
-            render_pass.set_pipeline(&self.canvas_render_pipeline);
-            render_pass.set_vertex_buffer(0, buffer.slice(..));
-            render_pass.set_bind_group(0, &self.offset_bind_group, &[]);
-            render_pass.draw(0..6, 0..1);
+    // Replays a recorded stroke into the layer it was drawn on
+    pub fn draw_stroke(&mut self, stroke: &Stroke) {
+        let active_layer = self.active_layer;
+        self.active_layer = stroke.layer.min(self.layers.len() - 1);
+        for dab in stroke.dabs() {
+            self.draw_dab(&dab);
         }
+        self.active_layer = active_layer;
+    }
+
+    // Maps canvas pixels to the clip space of a layer texture
+    fn layer_transform(canvas_size: wgpu::Extent3d) -> [f32; 4] {
+        [
+            2.0 / canvas_size.width as f32,
+            -2.0 / canvas_size.height as f32,
+            -1.0,
+            1.0,
+        ]
     }
+
+    // Maps canvas pixels to clip space and carries the shape color
+    fn shape_uniform(transform: [f32; 4], shape: &Shape) -> [f32; 8] {
+        let [sx, sy, tx, ty] = transform;
+        let [r, g, b, a] = color::to_linear(shape.color);
+        [sx, sy, tx, ty, r, g, b, a]
+    }
+
+    // Draws the shape into the active layer
+    pub fn draw_shape(&mut self, commands: &mut RenderCommands, shape: &Shape) {
+        let vertices = shape.tessellate();
+        if vertices.is_empty() {
+            return;
+        }
+        // Shapes are committed straight into the layer texture
+        let transform = Self::layer_transform(self.canvas_size);
+        self.queue.write_buffer(
+            &self.shape_uniform_buffer,
+            0,
+            bytemuck::cast_slice(&Self::shape_uniform(transform, shape)),
+        );
+        let buffer = self.make_test_buffer(&vertices);
+        let view = self.layers[self.active_layer].view();
+        let mut render_pass = commands.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("Draw Shape Render Pass"),
+            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                view: &view,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Load,
+                    store: wgpu::StoreOp::Store,
+                },
+            })],
+            depth_stencil_attachment: None,
+            timestamp_writes: None,
+            occlusion_query_set: None,
+        });
+        render_pass.set_pipeline(&self.shape_render_pipeline);
+        render_pass.set_vertex_buffer(0, buffer.slice(..));
+        render_pass.set_bind_group(0, &self.shape_bind_group, &[]);
+        render_pass.draw(0..vertices.len() as u32, 0..1);
+    }
+
+    // The preview is drawn over the layers by `render` and never touches them
+    pub fn set_shape_preview(&mut self, shape: Option<&Shape>) {
+        self.mark_dirty();
+        self.shape_preview = shape.and_then(|shape| {
+            let vertices = shape.tessellate();
+            if vertices.is_empty() {
+                return None;
+            }
+            let transform = self.viewport().canvas_to_ndc_transform();
+            self.queue.write_buffer(
+                &self.shape_preview_uniform_buffer,
+                0,
+                bytemuck::cast_slice(&Self::shape_uniform(transform, shape)),
+            );
+            Some((self.make_test_buffer(&vertices), vertices.len() as u32))
+        });
+    }
+
     //Creates a buffer for this state, which can be used to draw to the screen
-    pub fn make_test_buffer(&mut self, vertices: &[Vertex]) -> wgpu::Buffer {
+    pub fn make_test_buffer(&self, vertices: &[Vertex]) -> wgpu::Buffer {
         let vertex_buffer = self
             .device
             .create_buffer_init(&wgpu::util::BufferInitDescriptor {
                 label: Some("Vertex Buffer"),
-                contents: bytemuck::cast_slice(&vertices),
+                contents: bytemuck::cast_slice(vertices),
                 usage: wgpu::BufferUsages::VERTEX,
             });
         vertex_buffer
     }
+    // Presents the canvas on the window, does nothing when headless
     pub fn render(&mut self) -> Result<(), wgpu::SurfaceError> {
-        let output = self.surface.get_current_texture()?;
-        let view = output
-            .texture
-            .create_view(&wgpu::TextureViewDescriptor::default());
+        self.flush_dabs();
+        let Some(surface) = &self.surface else {
+            self.dirty = false;
+            return Ok(());
+        };
+        // Stays dirty when the frame can't be drawn, so it is tried again
+        let output = surface.get_current_texture()?;
+        self.dirty = false;
+        let view = output.texture.create_view(&wgpu::TextureViewDescriptor {
+            format: Some(Self::display_format(&self.config)),
+            ..Default::default()
+        });
         let mut encoder = self
             .device
             .create_command_encoder(&wgpu::CommandEncoderDescriptor {
@@ -375,9 +930,22 @@ impl State {
                 timestamp_writes: None,
                 occlusion_query_set: None,
             });
-            render_pass.set_pipeline(&self.display_render_pipeline);
-            render_pass.set_bind_group(0, &self.canvas_bind_group, &[]);
+            // The pipeline layout wants a layer in group 0 even though the background doesn't sample it
+            render_pass.set_pipeline(&self.background_pipeline);
+            render_pass.set_bind_group(0, &self.layers[0].bind_group, &[]);
+            render_pass.set_bind_group(1, &self.view_bind_group, &[]);
             render_pass.draw(0..6, 0..1);
+            self.composite_layers(
+                &mut render_pass,
+                &self.display_render_pipelines,
+                &self.view_bind_group,
+            );
+            if let Some((buffer, vertex_count)) = &self.shape_preview {
+                render_pass.set_pipeline(&self.shape_preview_pipeline);
+                render_pass.set_vertex_buffer(0, buffer.slice(..));
+                render_pass.set_bind_group(0, &self.shape_preview_bind_group, &[]);
+                render_pass.draw(0..*vertex_count, 0..1);
+            }
         }
         // submit will accept anything that implements IntoIter
         self.queue.submit(std::iter::once(encoder.finish()));
@@ -385,23 +953,146 @@ impl State {
 
         Ok(())
     }
+    // Draws every visible layer, bottom to top, with the pipeline matching its blend mode
+    fn composite_layers<'a>(
+        &'a self,
+        render_pass: &mut wgpu::RenderPass<'a>,
+        pipelines: &'a [wgpu::RenderPipeline; 4],
+        view_bind_group: &'a wgpu::BindGroup,
+    ) {
+        render_pass.set_bind_group(1, view_bind_group, &[]);
+        for layer in self.layers.iter().filter(|layer| layer.visible) {
+            render_pass.set_pipeline(&pipelines[layer.blend_mode as usize]);
+            render_pass.set_bind_group(0, &layer.bind_group, &[]);
+            render_pass.draw(0..6, 0..1);
+        }
+    }
     //The buffer for the copy operation must have the width a multiple of 256
     fn get_necessary_buffer_width(width: u32) -> u32 {
-        //TODO: replace chatgpt code here
-        let mut necessary_width = width;
-        while necessary_width % 256 != 0 {
-            necessary_width += 1;
+        width.next_multiple_of(256)
+    }
+    // The flattened canvas as sRGB with straight alpha, ready to be saved
+    pub async fn extract_framebuffer(&mut self) -> anyhow::Result<image::RgbaImage> {
+        self.export().await
+    }
+
+    // Like `extract_framebuffer`, without borrowing the state while waiting for the GPU.
+    // Transparent backgrounds and layers keep their alpha
+    pub fn export(&mut self) -> impl Future<Output = anyhow::Result<image::RgbaImage>> + 'static {
+        let flattened = self.flatten();
+        async move {
+            let mut image = flattened.await?;
+            for pixel in image.pixels_mut() {
+                *pixel = color::from_layer_pixel(*pixel);
+            }
+            Ok(image)
+        }
+    }
+
+    // Composites the background and the visible layers like the display pass does and reads the result back.
+    // The pixels are premultiplied like the layers', see `export` for straight alpha
+    pub fn flatten(&mut self) -> impl Future<Output = anyhow::Result<image::RgbaImage>> + 'static {
+        self.flush_dabs();
+        // The layers are flattened into a temporary texture, which is then copied out
+        let output = self.device.create_texture(&wgpu::TextureDescriptor {
+            size: self.canvas_size,
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: Layer::FORMAT,
+            usage: wgpu::TextureUsages::COPY_SRC | wgpu::TextureUsages::RENDER_ATTACHMENT,
+            label: Some("Flattened Canvas Texture"),
+            view_formats: &[Layer::FORMAT],
+        });
+        let output_view = output.create_view(&wgpu::TextureViewDescriptor::default());
+        let [r, g, b, a] = self.background.to_linear().map(f64::from);
+        let background = wgpu::Color { r, g, b, a };
+        let mut encoder = self
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+        {
+            let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("Flatten Layers Render Pass"),
+                color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                    view: &output_view,
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(background),
+                        store: wgpu::StoreOp::Store,
+                    },
+                })],
+                depth_stencil_attachment: None,
+                timestamp_writes: None,
+                occlusion_query_set: None,
+            });
+            self.composite_layers(
+                &mut render_pass,
+                &self.flatten_render_pipelines,
+                &self.identity_view_bind_group,
+            );
         }
-        necessary_width
+        self.queue.submit(Some(encoder.finish()));
+        self.read_texture(&output)
     }
-    //EXTRACTS THE FRAMEBUFFER FROM THE GPU, THE FORMAT IS NOT DEFINED YET(considered BGRA8Srgb for now)
-    //Since this is intended to only be called at the end of the program(and only once) it should be fine to allocate the buffer here
-    pub async fn extract_framebuffer(&self) -> image::RgbaImage {
+
+    // Reads back every layer along with its settings, the state can be unlocked while waiting
+    pub fn snapshot(&mut self) -> impl Future<Output = anyhow::Result<CanvasSnapshot>> + 'static {
+        let layers: Vec<_> = self
+            .layer_settings()
+            .into_iter()
+            .enumerate()
+            .map(|(index, settings)| (self.read_layer(index), settings))
+            .collect();
+        let (active_layer, view, background) = (self.active_layer, self.view, self.background);
+        async move {
+            let mut snapshot = CanvasSnapshot {
+                layers: Vec::with_capacity(layers.len()),
+                active_layer,
+                view,
+                background,
+            };
+            for (readback, settings) in layers {
+                snapshot.layers.push((readback.await?, settings));
+            }
+            Ok(snapshot)
+        }
+    }
+
+    // Puts a snapshot back into a freshly created state of the same canvas size. The undo history is not kept
+    pub fn restore(&mut self, snapshot: &CanvasSnapshot) {
+        for (index, (pixels, settings)) in snapshot.layers.iter().enumerate() {
+            if index > 0 {
+                self.apply_layer_command(LayerCommand::Add);
+            }
+            self.write_layer(index, pixels);
+            self.apply_layer_command(LayerCommand::SetOpacity(index, settings.opacity));
+            self.apply_layer_command(LayerCommand::SetVisible(index, settings.visible));
+            self.apply_layer_command(LayerCommand::SetBlendMode(index, settings.blend_mode));
+        }
+        self.apply_layer_command(LayerCommand::Select(snapshot.active_layer));
+        self.background = snapshot.background;
+        self.set_view(snapshot.view);
+    }
+
+    // Reads back the pixels of one layer, as stored (premultiplied)
+    pub fn read_layer(
+        &mut self,
+        index: usize,
+    ) -> impl Future<Output = anyhow::Result<image::RgbaImage>> + 'static {
+        self.flush_dabs();
+        self.read_texture(&self.layers[index].texture)
+    }
+
+    // Copies a canvas sized texture into a mappable buffer. The returned future doesn't borrow the state,
+    // so the state can be unlocked while waiting for the GPU
+    fn read_texture(
+        &self,
+        texture: &wgpu::Texture,
+    ) -> impl Future<Output = anyhow::Result<image::RgbaImage>> + 'static {
         let u32_size = std::mem::size_of::<u32>() as u32;
-        let output_buffer_size = (u32_size
-            * utils::WINDOW_HEIGHT
-            * Self::get_necessary_buffer_width(utils::WINDOW_WIDTH))
-            as wgpu::BufferAddress;
+        let padded_width = Self::get_necessary_buffer_width(self.canvas_size.width);
+        let output_buffer_size =
+            (u32_size * self.canvas_size.height * padded_width) as wgpu::BufferAddress;
         let output_buffer_desc = wgpu::BufferDescriptor {
             size: output_buffer_size,
             usage: wgpu::BufferUsages::COPY_DST
@@ -411,14 +1102,13 @@ impl State {
             mapped_at_creation: false,
         };
         let output_buffer = self.device.create_buffer(&output_buffer_desc);
-        let output = &self.canvas_texture;
         let mut encoder = self
             .device
             .create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
         encoder.copy_texture_to_buffer(
             wgpu::ImageCopyTexture {
                 aspect: wgpu::TextureAspect::All,
-                texture: &output,
+                texture,
                 mip_level: 0,
                 origin: wgpu::Origin3d::ZERO,
             },
@@ -426,72 +1116,125 @@ impl State {
                 buffer: &output_buffer,
                 layout: wgpu::ImageDataLayout {
                     offset: 0,
-                    bytes_per_row: Some(
-                        Self::get_necessary_buffer_width(utils::WINDOW_WIDTH) * u32_size,
-                    ),
+                    bytes_per_row: Some(padded_width * u32_size),
                     rows_per_image: None,
                 },
             },
-            wgpu::Extent3d {
-                width: utils::WINDOW_WIDTH,
-                height: utils::WINDOW_HEIGHT,
-                depth_or_array_layers: 1,
-            },
+            self.canvas_size,
         );
         self.queue.submit(Some(encoder.finish()));
-        {
-            // need 2 pipelines as we can't use the surface render pipeline because of the color attachment
-            let buffer_slice = output_buffer.slice(..);
-            // the future. Otherwise the application will freeze.
-            let (tx, rx) = futures_intrusive::channel::shared::oneshot_channel();
-            buffer_slice.map_async(wgpu::MapMode::Read, move |result| {
-                tx.send(result).unwrap();
+        // the future. Otherwise the application will freeze.
+        let (tx, rx) = futures_intrusive::channel::shared::oneshot_channel();
+        output_buffer
+            .slice(..)
+            .map_async(wgpu::MapMode::Read, move |result| {
+                // Nobody is waiting anymore if the receiver is gone
+                let _ = tx.send(result);
             });
-            self.device.poll(wgpu::Maintain::Wait);
-            rx.receive().await.unwrap().unwrap();
-
-            let data = buffer_slice.get_mapped_range();
-            image::RgbaImage::from_raw(
-                Self::get_necessary_buffer_width(utils::WINDOW_WIDTH),
-                utils::WINDOW_HEIGHT,
-                data.to_vec(),
-            )
-            .unwrap()
-            .sub_image(0, 0, utils::WINDOW_WIDTH, utils::WINDOW_HEIGHT)
-            .to_image()
+        // On native this blocks until the copy is done, on the web the browser resolves the mapping
+        self.device.poll(wgpu::Maintain::Wait);
+        let (width, height) = (self.canvas_size.width, self.canvas_size.height);
+        // The bytes are copied as they are stored, the image wants them in RGBA order
+        let bgra = match texture.format() {
+            TextureFormat::Rgba8Unorm | TextureFormat::Rgba8UnormSrgb => false,
+            TextureFormat::Bgra8Unorm | TextureFormat::Bgra8UnormSrgb => true,
+            format => panic!("Can't read back {:?} textures", format),
+        };
+        async move {
+            rx.receive()
+                .await
+                .context("The device was lost before the canvas was read back")?
+                .context("Failed to map the canvas readback buffer")?;
+            let data = output_buffer.slice(..).get_mapped_range();
+            let mut image = image::RgbaImage::from_raw(padded_width, height, data.to_vec())
+                .context("The canvas readback buffer is too small")?
+                .sub_image(0, 0, width, height)
+                .to_image();
+            if bgra {
+                for pixel in image.pixels_mut() {
+                    pixel.0.swap(0, 2);
+                }
+            }
+            Ok(image)
+        }
+    }
+
+    // Replaces the pixels of one layer, the image must have the size of the canvas
+    pub fn write_layer(&mut self, index: usize, image: &image::RgbaImage) {
+        self.flush_dabs();
+        self.queue.write_texture(
+            wgpu::ImageCopyTexture {
+                aspect: wgpu::TextureAspect::All,
+                texture: &self.layers[index].texture,
+                mip_level: 0,
+                origin: wgpu::Origin3d::ZERO,
+            },
+            image.as_raw(),
+            wgpu::ImageDataLayout {
+                offset: 0,
+                bytes_per_row: Some(4 * image.width()),
+                rows_per_image: None,
+            },
+            self.canvas_size,
+        );
+        self.mark_dirty();
+    }
+
+    // Saves a copy of the layer so the next change to it can be undone
+    pub fn push_undo(&mut self, index: usize, kind: UndoKind) {
+        let mut encoder = self.begin_render();
+        let snapshot = self.layers[index].snapshot(&self.device, &mut encoder);
+        // Copying the layer doesn't change what is on screen, so this doesn't need a new frame
+        self.queue.submit(Some(encoder.finish()));
+        if self.undo_stack.len() == Self::MAX_UNDO_STEPS {
+            self.undo_stack.pop_front();
         }
+        self.undo_stack.push_back((index, snapshot, kind));
+    }
+
+    // Returns what the undone step was recorded for, None when there is nothing left to undo
+    pub fn undo(&mut self) -> Option<UndoKind> {
+        let (index, snapshot, kind) = self.undo_stack.pop_back()?;
+        let mut encoder = self.begin_render();
+        self.layers[index].restore(&mut encoder, &snapshot);
+        self.end_render(encoder);
+        Some(kind)
     }
 
+    #[allow(clippy::too_many_arguments)]
     fn create_pipeline(
         device: &Device,
         format: TextureFormat,
-        shader: ShaderModule,
+        shader: &ShaderModule,
+        fragment_entry: &str,
         bind_group_layouts: &[&BindGroupLayout],
         buffers: &[VertexBufferLayout],
+        blend: wgpu::BlendState,
+        cull_mode: Option<wgpu::Face>,
     ) -> RenderPipeline {
         let render_pipeline_layout =
             device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
                 label: Some("Render Pipeline Layout"),
-                bind_group_layouts: bind_group_layouts,
+                bind_group_layouts,
                 push_constant_ranges: &[],
             });
         let screen_pipeline_fragment_target = [Some(wgpu::ColorTargetState {
-            format: format,
-            blend: Some(wgpu::BlendState::REPLACE),
+            format,
+            blend: Some(blend),
             write_mask: wgpu::ColorWrites::ALL,
         })];
         let screen_pipeline_descriptor = wgpu::RenderPipelineDescriptor {
             label: Some("Render Pipeline"),
             layout: Some(&render_pipeline_layout),
             vertex: wgpu::VertexState {
-                module: &shader,
+                module: shader,
                 entry_point: "vs_main",
                 buffers,
                 compilation_options: PipelineCompilationOptions::default(),
             },
             fragment: Some(wgpu::FragmentState {
-                module: &shader,
-                entry_point: "fs_main",
+                module: shader,
+                entry_point: fragment_entry,
                 targets: &screen_pipeline_fragment_target,
                 compilation_options: PipelineCompilationOptions::default(),
             }),
@@ -499,7 +1242,7 @@ impl State {
                 topology: wgpu::PrimitiveTopology::TriangleList, // 1.
                 strip_index_format: None,
                 front_face: wgpu::FrontFace::Ccw, // 2.
-                cull_mode: Some(wgpu::Face::Back),
+                cull_mode,
                 // Setting this to anything other than Fill requires Features::NON_FILL_POLYGON_MODE
                 polygon_mode: wgpu::PolygonMode::Fill,
                 // Requires Features::DEPTH_CLIP_CONTROL
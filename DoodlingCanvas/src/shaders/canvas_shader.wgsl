@@ -1,26 +1,40 @@
+struct BrushUniform {
+    // xy scales and zw translates canvas pixels into clip space
+    transform: vec4<f32>,
+}
 @group(0) @binding(0)
-var<uniform> offset: vec4<f32>;
+var<uniform> brush: BrushUniform;
 struct VertexInput {
-    @location(0) vert_pos: vec2<f32>,
+    // Corner of the unit square centered on the dab
+    @location(0) corner: vec2<f32>,
+}
+struct DabInput {
+    // Center in canvas pixels
+    @location(1) position: vec2<f32>,
+    @location(2) size: f32,
+    // Radians, clockwise on the canvas
+    @location(3) rotation: f32,
+    // Linear color with straight alpha
+    @location(4) color: vec4<f32>,
 }
 struct VertexOutput {
     @builtin(position) clip_position: vec4<f32>,
-    @location(0) vert_pos: vec3<f32>,
+    @location(0) color: vec4<f32>,
 }
 
 @vertex
-fn vs_main(
-    @builtin(vertex_index) in_vertex_index: u32,
-    in : VertexInput
-) -> VertexOutput {
+fn vs_main(in: VertexInput, dab: DabInput) -> VertexOutput {
     var out: VertexOutput;
-    out.clip_position = vec4<f32>(in.vert_pos.x + offset.x, in.vert_pos.y - offset.y, 0.0, 1.0);
-    out.vert_pos = out.clip_position.xyz;
+    let c = cos(dab.rotation);
+    let s = sin(dab.rotation);
+    let corner = vec2<f32>(in.corner.x * c - in.corner.y * s, in.corner.x * s + in.corner.y * c) * dab.size;
+    out.clip_position = vec4<f32>((dab.position + corner) * brush.transform.xy + brush.transform.zw, 0.0, 1.0);
+    // The layers are premultiplied
+    out.color = vec4<f32>(dab.color.rgb * dab.color.a, dab.color.a);
     return out;
 }
 
-
 @fragment
 fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
-    return vec4<f32>(0.0, 0.0, 0.0, 1.0);
-}
\ No newline at end of file
+    return in.color;
+}
@@ -4,13 +4,25 @@ struct VertexOutput {
     @location(1) vert_uv: vec2<f32>,
 }
 
+struct LayerUniform {
+    // x is the opacity, the rest is padding
+    opacity: vec4<f32>,
+}
 
+struct ViewUniform {
+    // xy scales and zw translates the full screen quad to where the canvas is shown
+    transform: vec4<f32>,
+    // Premultiplied linear color under the layers, transparent shows a checkerboard
+    background: vec4<f32>,
+}
+@group(1) @binding(0)
+var<uniform> view: ViewUniform;
 
 @vertex
 fn vs_main(
     @builtin(vertex_index) in_vertex_index: u32,
 ) -> VertexOutput {
-    //THIS IS HARDCODED BECAUSE IT SHOULD ALWAYS DRAW THE WHOLE TEXTURE
+    //THIS IS HARDCODED BECAUSE IT SHOULD ALWAYS DRAW THE WHOLE TEXTURE, the view transform places it on the surface
     var vertices : array<vec2<f32>, 6> = array<vec2<f32>, 6>(
         vec2<f32>(-1.0, 1.0),
         vec2<f32>(-1.0, -1.0),
@@ -28,7 +40,8 @@ fn vs_main(
         vec2<f32>(0.0, 0.0),
     );
     var out: VertexOutput;
-    out.clip_position = vec4<f32>(vertices[in_vertex_index].x , vertices[in_vertex_index].y , 0.0, 1.0);
+    let position = vertices[in_vertex_index] * view.transform.xy + view.transform.zw;
+    out.clip_position = vec4<f32>(position, 0.0, 1.0);
     out.vert_pos = out.clip_position.xyz;
     out.vert_uv = uvs[in_vertex_index];
     return out;
@@ -38,8 +51,23 @@ fn vs_main(
 var canvas: texture_2d<f32>;
 @group(0)@binding(1)
 var canvas_sampler: sampler;
+@group(0)@binding(2)
+var<uniform> layer: LayerUniform;
 
+// Layers are stored premultiplied, so the opacity scales every channel and the
+// blend mode is handled by the pipeline's blend state
 @fragment
 fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
-    return textureSample(canvas, canvas_sampler, in.vert_uv.xy);
-}
\ No newline at end of file
+    return textureSample(canvas, canvas_sampler, in.vert_uv.xy) * layer.opacity.x;
+}
+
+const CHECKER_SIZE: f32 = 8.0;
+
+// Drawn under the layers on the surface only, the flattened export uses the background as its clear color
+@fragment
+fn fs_background(in: VertexOutput) -> @location(0) vec4<f32> {
+    let cell = floor(in.clip_position.xy / CHECKER_SIZE);
+    let dark = (i32(cell.x) + i32(cell.y)) % 2 != 0;
+    let checker = select(1.0, 0.6, dark);
+    return view.background + vec4<f32>(vec3<f32>(checker), 1.0) * (1.0 - view.background.a);
+}
@@ -0,0 +1,27 @@
+struct ShapeUniform {
+    // xy scales and zw translates canvas pixels into clip space
+    transform: vec4<f32>,
+    // Linear color with straight alpha
+    color: vec4<f32>,
+}
+@group(0) @binding(0)
+var<uniform> shape: ShapeUniform;
+
+struct VertexInput {
+    @location(0) vert_pos: vec2<f32>,
+}
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+}
+
+@vertex
+fn vs_main(in: VertexInput) -> VertexOutput {
+    var out: VertexOutput;
+    out.clip_position = vec4<f32>(in.vert_pos * shape.transform.xy + shape.transform.zw, 0.0, 1.0);
+    return out;
+}
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    return vec4<f32>(shape.color.rgb * shape.color.a, shape.color.a);
+}
@@ -0,0 +1,159 @@
+use std::f32::consts::{FRAC_PI_4, TAU};
+
+use crate::render_state::Vertex;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ShapeKind {
+    Line,
+    Rectangle,
+    Ellipse,
+}
+
+/// A shape being dragged out on the canvas, all coordinates are in canvas pixels
+#[derive(Clone, Copy, Debug)]
+pub struct Shape {
+    pub kind: ShapeKind,
+    pub start: [f32; 2],
+    pub end: [f32; 2],
+    pub filled: bool,
+    pub thickness: f32,
+    pub color: image::Rgba<u8>,
+}
+
+impl Shape {
+    const ELLIPSE_SEGMENTS: usize = 64;
+
+    // Snaps lines to multiples of 45° and turns rectangles and ellipses into squares and circles
+    pub fn constrained(mut self) -> Self {
+        let dx = self.end[0] - self.start[0];
+        let dy = self.end[1] - self.start[1];
+        self.end = match self.kind {
+            ShapeKind::Line => {
+                let length = dx.hypot(dy);
+                let angle = (dy.atan2(dx) / FRAC_PI_4).round() * FRAC_PI_4;
+                [
+                    self.start[0] + length * angle.cos(),
+                    self.start[1] + length * angle.sin(),
+                ]
+            }
+            ShapeKind::Rectangle | ShapeKind::Ellipse => {
+                let side = dx.abs().max(dy.abs());
+                [
+                    self.start[0] + side.copysign(dx),
+                    self.start[1] + side.copysign(dy),
+                ]
+            }
+        };
+        self
+    }
+
+    // Triangle list covering the shape, in canvas pixels
+    pub fn tessellate(&self) -> Vec<Vertex> {
+        let half = self.thickness / 2.0;
+        match self.kind {
+            ShapeKind::Line => Self::line(self.start, self.end, half),
+            ShapeKind::Rectangle => {
+                let min = [
+                    self.start[0].min(self.end[0]),
+                    self.start[1].min(self.end[1]),
+                ];
+                let max = [
+                    self.start[0].max(self.end[0]),
+                    self.start[1].max(self.end[1]),
+                ];
+                if self.filled {
+                    Self::quad(
+                        [min[0], min[1]],
+                        [max[0], min[1]],
+                        [max[0], max[1]],
+                        [min[0], max[1]],
+                    )
+                } else {
+                    let outer = Self::rectangle_outline(min, max, half);
+                    let inner = Self::rectangle_outline(min, max, -half);
+                    Self::ring(&outer, &inner)
+                }
+            }
+            ShapeKind::Ellipse => {
+                let center = [
+                    (self.start[0] + self.end[0]) / 2.0,
+                    (self.start[1] + self.end[1]) / 2.0,
+                ];
+                let radius = [
+                    (self.end[0] - self.start[0]).abs() / 2.0,
+                    (self.end[1] - self.start[1]).abs() / 2.0,
+                ];
+                if self.filled {
+                    let outline = Self::ellipse_outline(center, radius, 0.0);
+                    (0..outline.len())
+                        .flat_map(|i| [center, outline[i], outline[(i + 1) % outline.len()]])
+                        .map(|position| Vertex { position })
+                        .collect()
+                } else {
+                    let outer = Self::ellipse_outline(center, radius, half);
+                    let inner = Self::ellipse_outline(center, radius, -half);
+                    Self::ring(&outer, &inner)
+                }
+            }
+        }
+    }
+
+    fn line(start: [f32; 2], end: [f32; 2], half: f32) -> Vec<Vertex> {
+        let (dx, dy) = (end[0] - start[0], end[1] - start[1]);
+        let length = dx.hypot(dy);
+        if length == 0.0 {
+            return Vec::new();
+        }
+        // Perpendicular to the line, scaled to half the thickness
+        let normal = [-dy / length * half, dx / length * half];
+        Self::quad(
+            [start[0] + normal[0], start[1] + normal[1]],
+            [end[0] + normal[0], end[1] + normal[1]],
+            [end[0] - normal[0], end[1] - normal[1]],
+            [start[0] - normal[0], start[1] - normal[1]],
+        )
+    }
+
+    fn quad(a: [f32; 2], b: [f32; 2], c: [f32; 2], d: [f32; 2]) -> Vec<Vertex> {
+        [a, b, c, c, d, a]
+            .into_iter()
+            .map(|position| Vertex { position })
+            .collect()
+    }
+
+    fn rectangle_outline(min: [f32; 2], max: [f32; 2], grow: f32) -> Vec<[f32; 2]> {
+        let (min, max) = (
+            [min[0] - grow, min[1] - grow],
+            [max[0] + grow, max[1] + grow],
+        );
+        vec![
+            [min[0], min[1]],
+            [max[0], min[1]],
+            [max[0], max[1]],
+            [min[0], max[1]],
+        ]
+    }
+
+    fn ellipse_outline(center: [f32; 2], radius: [f32; 2], grow: f32) -> Vec<[f32; 2]> {
+        let radius = [(radius[0] + grow).max(0.0), (radius[1] + grow).max(0.0)];
+        (0..Self::ELLIPSE_SEGMENTS)
+            .map(|i| {
+                let angle = TAU * i as f32 / Self::ELLIPSE_SEGMENTS as f32;
+                [
+                    center[0] + radius[0] * angle.cos(),
+                    center[1] + radius[1] * angle.sin(),
+                ]
+            })
+            .collect()
+    }
+
+    // Fills the band between two closed outlines with the same number of points
+    fn ring(outer: &[[f32; 2]], inner: &[[f32; 2]]) -> Vec<Vertex> {
+        (0..outer.len())
+            .flat_map(|i| {
+                let next = (i + 1) % outer.len();
+                Self::quad(outer[i], outer[next], inner[next], inner[i])
+            })
+            .collect()
+    }
+}
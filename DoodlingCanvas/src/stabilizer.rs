@@ -0,0 +1,366 @@
+use std::collections::VecDeque;
+
+use crate::stroke::StrokePoint;
+
+#[cfg(target_arch = "wasm32")]
+use wasm_bindgen::prelude::*;
+
+/// How pointer samples are smoothed before dabs are placed
+#[cfg_attr(target_arch = "wasm32", wasm_bindgen)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StabilizerMode {
+    Off,
+    // Averages the last few samples
+    MovingAverage,
+    // The brush is pulled behind the pointer on a string and only moves once it's taut
+    LazyString,
+    // Fits a Catmull-Rom spline through the averaged samples
+    CatmullRom,
+}
+
+// Samples averaged at full strength
+const MAX_WINDOW: usize = 16;
+// Canvas pixels of string at full strength
+const MAX_STRING_LENGTH: f32 = 40.0;
+// Length of the straight pieces a spline segment is split into, in canvas pixels
+const SPLINE_STEP: f32 = 4.0;
+
+fn lerp(from: &StrokePoint, to: &StrokePoint, t: f32) -> StrokePoint {
+    StrokePoint {
+        x: from.x + (to.x - from.x) * t,
+        y: from.y + (to.y - from.y) * t,
+        pressure: from.pressure + (to.pressure - from.pressure) * t,
+    }
+}
+
+fn distance(a: &StrokePoint, b: &StrokePoint) -> f32 {
+    (b.x - a.x).hypot(b.y - a.y)
+}
+
+struct MovingAverage {
+    window: usize,
+    samples: VecDeque<StrokePoint>,
+}
+
+impl MovingAverage {
+    fn new(strength: f32) -> Self {
+        Self {
+            window: 1 + (strength * (MAX_WINDOW - 1) as f32).round() as usize,
+            samples: VecDeque::new(),
+        }
+    }
+
+    fn average(&self) -> StrokePoint {
+        let count = self.samples.len() as f32;
+        let sum = self.samples.iter().fold((0.0, 0.0, 0.0), |sum, point| {
+            (sum.0 + point.x, sum.1 + point.y, sum.2 + point.pressure)
+        });
+        StrokePoint {
+            x: sum.0 / count,
+            y: sum.1 / count,
+            pressure: sum.2 / count,
+        }
+    }
+
+    fn push(&mut self, point: StrokePoint) -> StrokePoint {
+        self.samples.push_back(point);
+        if self.samples.len() > self.window {
+            self.samples.pop_front();
+        }
+        self.average()
+    }
+
+    // Lets the average catch up with the last sample
+    fn finish(&mut self) -> Vec<StrokePoint> {
+        let mut points = Vec::new();
+        while self.samples.len() > 1 {
+            self.samples.pop_front();
+            points.push(self.average());
+        }
+        points
+    }
+}
+
+#[derive(Default)]
+struct Spline {
+    // The last four control points at most
+    controls: Vec<StrokePoint>,
+}
+
+impl Spline {
+    // Points along the segment from p1 to p2, excluding p1
+    fn segment(
+        p0: &StrokePoint,
+        p1: &StrokePoint,
+        p2: &StrokePoint,
+        p3: &StrokePoint,
+    ) -> Vec<StrokePoint> {
+        let steps = (distance(p1, p2) / SPLINE_STEP).ceil().max(1.0) as usize;
+        let curve = |a: f32, b: f32, c: f32, d: f32, t: f32| {
+            0.5 * (2.0 * b
+                + (c - a) * t
+                + (2.0 * a - 5.0 * b + 4.0 * c - d) * t * t
+                + (3.0 * b - a - 3.0 * c + d) * t * t * t)
+        };
+        (1..=steps)
+            .map(|step| {
+                let t = step as f32 / steps as f32;
+                StrokePoint {
+                    x: curve(p0.x, p1.x, p2.x, p3.x, t),
+                    y: curve(p0.y, p1.y, p2.y, p3.y, t),
+                    pressure: lerp(p1, p2, t).pressure,
+                }
+            })
+            .collect()
+    }
+
+    fn push(&mut self, point: StrokePoint) -> Vec<StrokePoint> {
+        self.controls.push(point);
+        if self.controls.len() > 4 {
+            self.controls.remove(0);
+        }
+        match self.controls.as_slice() {
+            [first] => vec![*first],
+            // The tangent at the second point needs the third one
+            [_, _] => Vec::new(),
+            [p0, p1, p2] => Self::segment(p0, p0, p1, p2),
+            [p0, p1, p2, p3] => Self::segment(p0, p1, p2, p3),
+            _ => unreachable!(),
+        }
+    }
+
+    // Draws the last segment, which was waiting for a following point
+    fn finish(&mut self) -> Vec<StrokePoint> {
+        let count = self.controls.len();
+        if count < 2 {
+            return Vec::new();
+        }
+        let p0 = &self.controls[count.saturating_sub(3)];
+        let p1 = &self.controls[count - 2];
+        let p2 = &self.controls[count - 1];
+        Self::segment(p0, p1, p2, p2)
+    }
+}
+
+/// Turns raw pointer samples into the points the brush is stamped along.
+/// Strength goes from 0 (no smoothing) to 1.
+pub struct Stabilizer {
+    mode: StabilizerMode,
+    strength: f32,
+    average: MovingAverage,
+    spline: Spline,
+    // Where the end of the lazy string is
+    brush: Option<StrokePoint>,
+}
+
+impl Stabilizer {
+    pub fn new(mode: StabilizerMode, strength: f32) -> Self {
+        let strength = strength.clamp(0.0, 1.0);
+        Self {
+            mode,
+            strength,
+            average: MovingAverage::new(strength),
+            spline: Spline::default(),
+            brush: None,
+        }
+    }
+
+    // Feeds a new sample, returning the smoothed points that are ready to be drawn
+    pub fn push(&mut self, point: StrokePoint) -> Vec<StrokePoint> {
+        match self.mode {
+            StabilizerMode::Off => vec![point],
+            StabilizerMode::MovingAverage => vec![self.average.push(point)],
+            StabilizerMode::LazyString => {
+                let Some(brush) = self.brush else {
+                    self.brush = Some(point);
+                    return vec![point];
+                };
+                let length = self.strength * MAX_STRING_LENGTH;
+                let stretch = distance(&brush, &point);
+                if stretch <= length {
+                    return Vec::new();
+                }
+                let mut moved = lerp(&brush, &point, (stretch - length) / stretch);
+                moved.pressure = point.pressure;
+                self.brush = Some(moved);
+                vec![moved]
+            }
+            StabilizerMode::CatmullRom => {
+                let control = self.average.push(point);
+                self.spline.push(control)
+            }
+        }
+    }
+
+    // Called when the stroke ends, returns the points that were held back
+    pub fn finish(&mut self) -> Vec<StrokePoint> {
+        let points = match self.mode {
+            // The string stays slack, stopping short of the pointer is the point of it
+            StabilizerMode::Off | StabilizerMode::LazyString => Vec::new(),
+            StabilizerMode::MovingAverage => self.average.finish(),
+            StabilizerMode::CatmullRom => {
+                let mut points = Vec::new();
+                for control in self.average.finish() {
+                    points.extend(self.spline.push(control));
+                }
+                points.extend(self.spline.finish());
+                points
+            }
+        };
+        *self = Self::new(self.mode, self.strength);
+        points
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn point(x: f32, y: f32) -> StrokePoint {
+        StrokePoint {
+            x,
+            y,
+            pressure: 1.0,
+        }
+    }
+
+    fn run(stabilizer: &mut Stabilizer, points: &[StrokePoint]) -> Vec<StrokePoint> {
+        let mut output: Vec<StrokePoint> = points
+            .iter()
+            .flat_map(|point| stabilizer.push(*point))
+            .collect();
+        output.extend(stabilizer.finish());
+        output
+    }
+
+    // A horizontal line with the pointer wobbling up and down
+    fn jittery_line() -> Vec<StrokePoint> {
+        (0..40)
+            .map(|i| point(i as f32 * 5.0, if i % 2 == 0 { 3.0 } else { -3.0 }))
+            .collect()
+    }
+
+    fn max_abs_y(points: &[StrokePoint]) -> f32 {
+        points.iter().map(|point| point.y.abs()).fold(0.0, f32::max)
+    }
+
+    fn assert_close(actual: &StrokePoint, expected: &StrokePoint) {
+        assert!(
+            distance(actual, expected) < 1e-3,
+            "{:?} != {:?}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn off_passes_samples_through() {
+        let input = jittery_line();
+        let output = run(&mut Stabilizer::new(StabilizerMode::Off, 1.0), &input);
+        assert_eq!(output, input);
+    }
+
+    #[test]
+    fn zero_strength_average_passes_samples_through() {
+        let input = jittery_line();
+        let output = run(
+            &mut Stabilizer::new(StabilizerMode::MovingAverage, 0.0),
+            &input,
+        );
+        assert_eq!(output, input);
+    }
+
+    #[test]
+    fn moving_average_removes_jitter_and_reaches_the_end() {
+        let input = jittery_line();
+        let output = run(
+            &mut Stabilizer::new(StabilizerMode::MovingAverage, 0.5),
+            &input,
+        );
+        // The average only converges on the last sample once the stroke ends
+        assert!(max_abs_y(&output[8..input.len()]) < 0.5);
+        assert_close(output.last().unwrap(), input.last().unwrap());
+    }
+
+    #[test]
+    fn lazy_string_waits_until_taut() {
+        let mut stabilizer = Stabilizer::new(StabilizerMode::LazyString, 0.5);
+        assert_eq!(stabilizer.push(point(0.0, 0.0)), vec![point(0.0, 0.0)]);
+        // Inside the 20 pixel string
+        assert!(stabilizer.push(point(15.0, 0.0)).is_empty());
+        assert!(stabilizer.push(point(0.0, -19.0)).is_empty());
+        // Pulled 10 pixels past the string's length
+        let moved = stabilizer.push(point(30.0, 0.0));
+        assert_eq!(moved.len(), 1);
+        assert_close(&moved[0], &point(10.0, 0.0));
+        let moved = stabilizer.push(point(30.0, 30.0));
+        assert!((distance(&moved[0], &point(30.0, 30.0)) - 20.0).abs() < 1e-3);
+        assert!(stabilizer.finish().is_empty());
+    }
+
+    #[test]
+    fn lazy_string_follows_pressure() {
+        let mut stabilizer = Stabilizer::new(StabilizerMode::LazyString, 0.25);
+        stabilizer.push(point(0.0, 0.0));
+        let moved = stabilizer.push(StrokePoint {
+            x: 50.0,
+            y: 0.0,
+            pressure: 0.3,
+        });
+        assert_eq!(moved[0].pressure, 0.3);
+    }
+
+    #[test]
+    fn catmull_rom_passes_through_samples() {
+        let input = [
+            point(0.0, 0.0),
+            point(20.0, 10.0),
+            point(40.0, -10.0),
+            point(60.0, 0.0),
+        ];
+        let output = run(
+            &mut Stabilizer::new(StabilizerMode::CatmullRom, 0.0),
+            &input,
+        );
+        for sample in &input {
+            assert!(output.iter().any(|point| distance(point, sample) < 1e-3));
+        }
+        assert_close(&output[0], &input[0]);
+        assert_close(output.last().unwrap(), input.last().unwrap());
+        // Steps are based on the chord, the curve bulges out a little further
+        for pair in output.windows(2) {
+            assert!(distance(&pair[0], &pair[1]) <= SPLINE_STEP * 1.5);
+        }
+    }
+
+    #[test]
+    fn catmull_rom_keeps_straight_lines_straight() {
+        let input: Vec<StrokePoint> = (0..10).map(|i| point(i as f32 * 10.0, 5.0)).collect();
+        let output = run(
+            &mut Stabilizer::new(StabilizerMode::CatmullRom, 0.3),
+            &input,
+        );
+        assert!(output.iter().all(|point| (point.y - 5.0).abs() < 1e-3));
+        assert_close(output.last().unwrap(), input.last().unwrap());
+    }
+
+    #[test]
+    fn catmull_rom_smooths_jitter() {
+        let input = jittery_line();
+        let output = run(
+            &mut Stabilizer::new(StabilizerMode::CatmullRom, 0.5),
+            &input,
+        );
+        assert!(max_abs_y(&output[output.len() / 4..output.len() * 3 / 4]) < 0.5);
+    }
+
+    #[test]
+    fn finish_resets_for_the_next_stroke() {
+        let mut stabilizer = Stabilizer::new(StabilizerMode::MovingAverage, 1.0);
+        run(&mut stabilizer, &jittery_line());
+        assert_eq!(
+            stabilizer.push(point(500.0, 500.0)),
+            vec![point(500.0, 500.0)]
+        );
+    }
+}
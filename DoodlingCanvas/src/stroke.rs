@@ -0,0 +1,123 @@
+/// Maps the raw pen pressure to how strongly the brush responds.
+/// An exponent of 1 is linear, above 1 light touches get thinner and fainter, below 1 they get stronger.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PressureCurve {
+    pub exponent: f32,
+}
+
+impl Default for PressureCurve {
+    fn default() -> Self {
+        Self { exponent: 1.0 }
+    }
+}
+
+impl PressureCurve {
+    pub fn apply(self, pressure: f32) -> f32 {
+        pressure.clamp(0.0, 1.0).powf(self.exponent)
+    }
+}
+
+/// One input sample of a brush stroke.
+/// Pressure is kept raw (0 to 1, 1 for mice) so a replay can use a different curve.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct StrokePoint {
+    // Canvas pixels
+    pub x: f32,
+    pub y: f32,
+    pub pressure: f32,
+}
+
+/// A single stamp of the brush
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Dab {
+    // Center of the dab in canvas pixels
+    pub position: [f32; 2],
+    pub size: f32,
+    // Radians, clockwise on the canvas
+    pub rotation: f32,
+    // sRGB with straight alpha
+    pub color: image::Rgba<u8>,
+}
+
+/// Everything needed to draw a brush stroke again.
+/// The points are the ones left after stabilizing, so replaying doesn't need the stabilizer settings.
+#[derive(Clone, Debug)]
+pub struct Stroke {
+    pub layer: usize,
+    pub color: image::Rgba<u8>,
+    pub size: f32,
+    pub pressure_curve: PressureCurve,
+    pub points: Vec<StrokePoint>,
+    // Distance covered since the last dab, carried over between points
+    since_dab: f32,
+}
+
+impl Stroke {
+    pub fn new(
+        layer: usize,
+        color: image::Rgba<u8>,
+        size: f32,
+        pressure_curve: PressureCurve,
+    ) -> Self {
+        Self {
+            layer,
+            color,
+            size,
+            pressure_curve,
+            points: Vec::new(),
+            since_dab: 0.0,
+        }
+    }
+
+    // Dabs overlap by three quarters of their size so the stroke looks continuous
+    fn spacing(&self) -> f32 {
+        (self.size / 4.0).max(1.0)
+    }
+
+    // Adds a point to the stroke, returning the dabs placed between the previous point and this one
+    pub fn push(&mut self, point: StrokePoint) -> Vec<Dab> {
+        let Some(last) = self.points.last().copied() else {
+            self.points.push(point);
+            return vec![self.dab(&point)];
+        };
+        let spacing = self.spacing();
+        let length = (point.x - last.x).hypot(point.y - last.y);
+        let mut dabs = Vec::new();
+        let mut along = spacing - self.since_dab;
+        while along <= length {
+            let t = along / length;
+            dabs.push(self.dab(&StrokePoint {
+                x: last.x + (point.x - last.x) * t,
+                y: last.y + (point.y - last.y) * t,
+                pressure: last.pressure + (point.pressure - last.pressure) * t,
+            }));
+            along += spacing;
+        }
+        self.since_dab = length - (along - spacing);
+        self.points.push(point);
+        dabs
+    }
+
+    // Pressure scales both the size and the opacity of the dab
+    pub fn dab(&self, point: &StrokePoint) -> Dab {
+        let pressure = self.pressure_curve.apply(point.pressure);
+        let mut color = self.color;
+        color.0[3] = (color.0[3] as f32 * pressure).round() as u8;
+        Dab {
+            position: [point.x, point.y],
+            size: (self.size * pressure).max(1.0),
+            // The brush is a square that stays axis aligned
+            rotation: 0.0,
+            color,
+        }
+    }
+
+    // Every dab of the stroke, the same ones that were placed while it was drawn
+    pub fn dabs(&self) -> Vec<Dab> {
+        let mut replay = Stroke::new(self.layer, self.color, self.size, self.pressure_curve);
+        self.points
+            .iter()
+            .flat_map(|point| replay.push(*point))
+            .collect()
+    }
+}
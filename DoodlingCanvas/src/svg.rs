@@ -0,0 +1,229 @@
+use std::fmt::Write;
+
+use crate::{
+    document::StrokeDocument,
+    layers::{Background, BlendMode},
+    stroke::{Stroke, StrokePoint},
+};
+
+// Numbers are written with at most two decimals, finer than anyone can see
+fn number(value: f32) -> String {
+    let text = format!("{:.2}", value);
+    let text = text.trim_end_matches('0').trim_end_matches('.');
+    match text {
+        "-0" => "0".to_owned(),
+        text => text.to_owned(),
+    }
+}
+
+// CSS blend modes doing what the pipeline blend states do
+fn mix_blend_mode(mode: BlendMode) -> Option<&'static str> {
+    match mode {
+        BlendMode::Normal => None,
+        BlendMode::Multiply => Some("multiply"),
+        BlendMode::Screen => Some("screen"),
+        BlendMode::Add => Some("plus-lighter"),
+    }
+}
+
+// Width and opacity of the brush at a point, the same the dab placed there gets
+fn brush_at(stroke: &Stroke, point: &StrokePoint) -> (String, String) {
+    let dab = stroke.dab(point);
+    (number(dab.size), number(dab.color.0[3] as f32 / 255.0))
+}
+
+// One path per run of points with the same width and opacity, SVG strokes can't vary along a path.
+// Runs share their end points so the stroke stays connected
+fn write_stroke(svg: &mut String, stroke: &Stroke) {
+    let [r, g, b, _] = stroke.color.0;
+    let mut start = 0;
+    while start < stroke.points.len() {
+        let brush = brush_at(
+            stroke,
+            &stroke.points[(start + 1).min(stroke.points.len() - 1)],
+        );
+        let mut end = start + 1;
+        while end + 1 < stroke.points.len() && brush_at(stroke, &stroke.points[end + 1]) == brush {
+            end += 1;
+        }
+        let end = end.min(stroke.points.len() - 1);
+        let mut path = String::new();
+        for (index, point) in stroke.points[start..=end].iter().enumerate() {
+            let command = if index == 0 { 'M' } else { 'L' };
+            let _ = write!(path, "{}{} {} ", command, number(point.x), number(point.y));
+        }
+        // A single point still shows a square, like the dab it draws
+        if start == end {
+            let point = &stroke.points[start];
+            let _ = write!(path, "L{} {}", number(point.x), number(point.y));
+        }
+        let (width, opacity) = brush;
+        let _ = writeln!(
+            svg,
+            r##"<path d="{}" stroke="#{:02x}{:02x}{:02x}" stroke-opacity="{}" stroke-width="{}"/>"##,
+            path.trim_end(),
+            r,
+            g,
+            b,
+            opacity,
+            width
+        );
+        if end + 1 >= stroke.points.len() {
+            break;
+        }
+        start = end;
+    }
+}
+
+/// The brush strokes of a document as an SVG document, for printing or using the doodle elsewhere.
+/// Each stroke becomes paths as wide as the brush, in its color and opacity, grouped by layer.
+/// The brush is square, so the paths use square caps. Fails for documents of canvases with fills, shapes
+/// or imported images, those aren't recorded
+pub fn to_svg(document: &StrokeDocument) -> anyhow::Result<String> {
+    document.ensure_complete()?;
+    let (width, height) = (document.width, document.height);
+    let mut svg = String::new();
+    let _ = writeln!(
+        svg,
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="{}" height="{}" viewBox="0 0 {} {}">"#,
+        width, height, width, height
+    );
+    if let Background::Color(color) = document.background {
+        let [r, g, b, a] = color.0;
+        let _ = writeln!(
+            svg,
+            r##"<rect width="{}" height="{}" fill="#{:02x}{:02x}{:02x}" fill-opacity="{}"/>"##,
+            width,
+            height,
+            r,
+            g,
+            b,
+            number(a as f32 / 255.0)
+        );
+    }
+    for (index, layer) in document.layers.iter().enumerate() {
+        if !layer.visible {
+            continue;
+        }
+        let _ = write!(
+            svg,
+            r#"<g opacity="{}" fill="none" stroke-linecap="square" stroke-linejoin="round""#,
+            number(layer.opacity)
+        );
+        if let Some(mode) = mix_blend_mode(layer.blend_mode) {
+            let _ = write!(svg, r#" style="mix-blend-mode:{}""#, mode);
+        }
+        svg.push_str(">\n");
+        for stroke in document
+            .strokes
+            .iter()
+            .filter(|stroke| stroke.layer == index)
+        {
+            write_stroke(&mut svg, &stroke.to_stroke()?);
+        }
+        svg.push_str("</g>\n");
+    }
+    svg.push_str("</svg>\n");
+    Ok(svg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::document::{LayerSettings, StrokeData};
+
+    fn document(strokes: Vec<StrokeData>) -> StrokeDocument {
+        StrokeDocument {
+            width: 100,
+            height: 80,
+            layers: vec![LayerSettings::default()],
+            strokes,
+            background: Background::Transparent,
+            incomplete: false,
+        }
+    }
+
+    fn stroke(color: &str, points: Vec<[f32; 3]>) -> StrokeData {
+        StrokeData {
+            layer: 0,
+            color: color.to_owned(),
+            size: 10.0,
+            pressure_curve: 1.0,
+            points,
+        }
+    }
+
+    #[test]
+    fn constant_pressure_is_one_path() {
+        let svg = to_svg(&document(vec![stroke(
+            "#ff000080",
+            vec![[1.0, 2.0, 1.0], [50.5, 2.0, 1.0], [50.5, 70.25, 1.0]],
+        )]))
+        .unwrap();
+        assert_eq!(svg.matches("<path").count(), 1);
+        assert!(svg.contains(
+            r##"<path d="M1 2 L50.5 2 L50.5 70.25" stroke="#ff0000" stroke-opacity="0.5" stroke-width="10"/>"##
+        ));
+        assert!(!svg.contains("<rect"));
+    }
+
+    #[test]
+    fn pressure_changes_split_the_path() {
+        let svg = to_svg(&document(vec![stroke(
+            "#000000",
+            vec![
+                [0.0, 0.0, 1.0],
+                [10.0, 0.0, 1.0],
+                [20.0, 0.0, 0.5],
+                [30.0, 0.0, 0.5],
+            ],
+        )]))
+        .unwrap();
+        assert!(svg
+            .contains(r##"d="M0 0 L10 0" stroke="#000000" stroke-opacity="1" stroke-width="10""##));
+        assert!(svg.contains(
+            r##"d="M10 0 L20 0 L30 0" stroke="#000000" stroke-opacity="0.5" stroke-width="5""##
+        ));
+    }
+
+    #[test]
+    fn single_points_still_draw() {
+        let svg = to_svg(&document(vec![stroke("#000000", vec![[4.0, 5.0, 1.0]])])).unwrap();
+        assert!(svg.contains(r#"d="M4 5 L4 5""#));
+    }
+
+    #[test]
+    fn layers_keep_their_settings() {
+        let mut document = document(vec![stroke("#000000", vec![[4.0, 5.0, 1.0]])]);
+        document.background = Background::default();
+        document.layers = vec![
+            LayerSettings {
+                opacity: 0.25,
+                blend_mode: BlendMode::Multiply,
+                ..LayerSettings::default()
+            },
+            LayerSettings {
+                visible: false,
+                ..LayerSettings::default()
+            },
+        ];
+        document.strokes.push(StrokeData {
+            layer: 1,
+            ..stroke("#ffffff", vec![[1.0, 1.0, 1.0]])
+        });
+        let svg = to_svg(&document).unwrap();
+        assert!(
+            svg.contains(r##"<rect width="100" height="80" fill="#7c7c7c" fill-opacity="1"/>"##)
+        );
+        assert!(svg.contains(r#"opacity="0.25""#) && svg.contains("mix-blend-mode:multiply"));
+        // Hidden layers are left out
+        assert!(!svg.contains("#ffffff"));
+    }
+
+    #[test]
+    fn incomplete_documents_are_refused() {
+        let mut document = document(vec![stroke("#000000", vec![[4.0, 5.0, 1.0]])]);
+        document.incomplete = true;
+        assert!(to_svg(&document).is_err());
+    }
+}
@@ -0,0 +1,87 @@
+use crate::{shapes::ShapeKind, stabilizer::StabilizerMode, stroke::PressureCurve};
+
+#[cfg(target_arch = "wasm32")]
+use wasm_bindgen::prelude::*;
+
+#[cfg_attr(target_arch = "wasm32", wasm_bindgen)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Tool {
+    Brush,
+    Line,
+    Rectangle,
+    Ellipse,
+    Fill,
+    Eyedropper,
+}
+
+impl Tool {
+    pub fn shape_kind(self) -> Option<ShapeKind> {
+        match self {
+            Tool::Brush | Tool::Fill | Tool::Eyedropper => None,
+            Tool::Line => Some(ShapeKind::Line),
+            Tool::Rectangle => Some(ShapeKind::Rectangle),
+            Tool::Ellipse => Some(ShapeKind::Ellipse),
+        }
+    }
+}
+
+/// Changes to the tool settings requested from outside the event loop
+#[derive(Debug, Clone, Copy)]
+pub enum ToolCommand {
+    SetTool(Tool),
+    SetColor(image::Rgba<u8>),
+    SetSize(f32),
+    SetShapeFilled(bool),
+    SetFillTolerance(u8),
+    SetPressureCurve(PressureCurve),
+    SetStabilizerMode(StabilizerMode),
+    SetStabilizerStrength(f32),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ToolSettings {
+    pub tool: Tool,
+    // sRGB with straight alpha
+    pub color: image::Rgba<u8>,
+    // Brush size and shape outline thickness, in canvas pixels
+    pub size: f32,
+    pub shape_filled: bool,
+    // Largest per channel difference from the clicked color that still gets filled
+    pub fill_tolerance: u8,
+    pub pressure_curve: PressureCurve,
+    pub stabilizer_mode: StabilizerMode,
+    // From 0 to 1
+    pub stabilizer_strength: f32,
+}
+
+impl Default for ToolSettings {
+    fn default() -> Self {
+        Self {
+            tool: Tool::Brush,
+            color: image::Rgba([0, 0, 0, 255]),
+            size: 10.0,
+            shape_filled: false,
+            fill_tolerance: 0,
+            pressure_curve: PressureCurve::default(),
+            stabilizer_mode: StabilizerMode::Off,
+            stabilizer_strength: 0.5,
+        }
+    }
+}
+
+impl ToolSettings {
+    pub fn apply(&mut self, command: ToolCommand) {
+        match command {
+            ToolCommand::SetTool(tool) => self.tool = tool,
+            ToolCommand::SetColor(color) => self.color = color,
+            ToolCommand::SetSize(size) => self.size = size.max(1.0),
+            ToolCommand::SetShapeFilled(filled) => self.shape_filled = filled,
+            ToolCommand::SetFillTolerance(tolerance) => self.fill_tolerance = tolerance,
+            ToolCommand::SetPressureCurve(curve) => self.pressure_curve = curve,
+            ToolCommand::SetStabilizerMode(mode) => self.stabilizer_mode = mode,
+            ToolCommand::SetStabilizerStrength(strength) => {
+                self.stabilizer_strength = strength.clamp(0.0, 1.0)
+            }
+        }
+    }
+}
@@ -1,3 +1,30 @@
+#[cfg(feature = "render")]
+use std::future::Future;
 
-pub const WINDOW_WIDTH: u32 = 800;
-pub const WINDOW_HEIGHT: u32 = 600;
\ No newline at end of file
+pub const DEFAULT_CANVAS_WIDTH: u32 = 800;
+pub const DEFAULT_CANVAS_HEIGHT: u32 = 600;
+// WebGL2 only guarantees 2048x2048 textures. DoodlingServer accepts doodles of the same sizes
+pub const MIN_CANVAS_SIZE: u32 = 64;
+pub const MAX_CANVAS_SIZE: u32 = 2048;
+// Where the desktop build saves the canvas unless told otherwise
+pub const DEFAULT_OUTPUT_PATH: &str = "doodle.png";
+
+// Clamps each dimension of the requested canvas size to the supported range
+pub fn clamp_canvas_size(width: u32, height: u32) -> (u32, u32) {
+    (
+        width.clamp(MIN_CANVAS_SIZE, MAX_CANVAS_SIZE),
+        height.clamp(MIN_CANVAS_SIZE, MAX_CANVAS_SIZE),
+    )
+}
+
+// Runs the future to completion, on the web it's handed to the browser instead of blocking
+#[cfg(feature = "render")]
+pub fn spawn<F: Future<Output = ()> + 'static>(future: F) {
+    cfg_if::cfg_if! {
+    if #[cfg(target_arch = "wasm32")] {
+        wasm_bindgen_futures::spawn_local(future);
+    } else {
+        pollster::block_on(future);
+    }
+    }
+}
@@ -0,0 +1,284 @@
+use winit::dpi::PhysicalSize;
+
+/// Zoom and pan applied by the user on top of fitting the canvas to the window
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ViewTransform {
+    pub zoom: f32,
+    // In window pixels, relative to the centered canvas
+    pub pan: (f32, f32),
+}
+
+impl Default for ViewTransform {
+    // Fit to window
+    fn default() -> Self {
+        Self {
+            zoom: 1.0,
+            pan: (0.0, 0.0),
+        }
+    }
+}
+
+impl ViewTransform {
+    pub const MIN_ZOOM: f32 = 0.1;
+    pub const MAX_ZOOM: f32 = 32.0;
+
+    pub fn panned(self, delta: (f32, f32)) -> Self {
+        Self {
+            pan: (self.pan.0 + delta.0, self.pan.1 + delta.1),
+            ..self
+        }
+    }
+}
+
+/// Maps between the three coordinate spaces used by the widget:
+/// window coordinates (physical pixels of the surface, as reported by winit pointer events),
+/// canvas coordinates (pixels of the layer textures) and normalized device coordinates of the surface.
+/// The canvas is scaled to fit the window while keeping its aspect ratio and centered, leaving bars on the sides,
+/// then the view transform zooms and pans it.
+/// Everything is in physical pixels, so on a HiDPI screen the scale factor only shows up as a bigger window size.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Viewport {
+    window_size: (f32, f32),
+    canvas_size: (f32, f32),
+    view: ViewTransform,
+}
+
+impl Viewport {
+    pub fn new(window_size: PhysicalSize<u32>, canvas_size: PhysicalSize<u32>) -> Self {
+        Self::with_view(window_size, canvas_size, ViewTransform::default())
+    }
+
+    pub fn with_view(
+        window_size: PhysicalSize<u32>,
+        canvas_size: PhysicalSize<u32>,
+        view: ViewTransform,
+    ) -> Self {
+        Self {
+            window_size: (
+                window_size.width.max(1) as f32,
+                window_size.height.max(1) as f32,
+            ),
+            canvas_size: (
+                canvas_size.width.max(1) as f32,
+                canvas_size.height.max(1) as f32,
+            ),
+            view,
+        }
+    }
+
+    // Window pixels per canvas pixel
+    pub fn scale(&self) -> f32 {
+        self.fit_scale() * self.view.zoom
+    }
+
+    fn fit_scale(&self) -> f32 {
+        (self.window_size.0 / self.canvas_size.0).min(self.window_size.1 / self.canvas_size.1)
+    }
+
+    // Top left corner of the canvas in window pixels
+    pub fn offset(&self) -> (f32, f32) {
+        let centered = self.centered_offset(self.scale());
+        (centered.0 + self.view.pan.0, centered.1 + self.view.pan.1)
+    }
+
+    fn centered_offset(&self, scale: f32) -> (f32, f32) {
+        (
+            (self.window_size.0 - self.canvas_size.0 * scale) / 2.0,
+            (self.window_size.1 - self.canvas_size.1 * scale) / 2.0,
+        )
+    }
+
+    // Multiplies the zoom by `factor` while keeping the canvas point under `position` (window pixels) in place
+    pub fn zoom_at(&self, position: (f32, f32), factor: f32) -> ViewTransform {
+        let anchor = self.window_to_canvas(position);
+        let zoom =
+            (self.view.zoom * factor).clamp(ViewTransform::MIN_ZOOM, ViewTransform::MAX_ZOOM);
+        let scale = self.fit_scale() * zoom;
+        let centered = self.centered_offset(scale);
+        ViewTransform {
+            zoom,
+            pan: (
+                position.0 - anchor.0 * scale - centered.0,
+                position.1 - anchor.1 * scale - centered.1,
+            ),
+        }
+    }
+
+    pub fn window_to_canvas(&self, position: (f32, f32)) -> (f32, f32) {
+        let scale = self.scale();
+        let offset = self.offset();
+        (
+            (position.0 - offset.0) / scale,
+            (position.1 - offset.1) / scale,
+        )
+    }
+
+    pub fn canvas_to_window(&self, position: (f32, f32)) -> (f32, f32) {
+        let scale = self.scale();
+        let offset = self.offset();
+        (position.0 * scale + offset.0, position.1 * scale + offset.1)
+    }
+
+    pub fn window_to_ndc(&self, position: (f32, f32)) -> (f32, f32) {
+        (
+            position.0 / self.window_size.0 * 2.0 - 1.0,
+            1.0 - position.1 / self.window_size.1 * 2.0,
+        )
+    }
+
+    pub fn canvas_to_ndc(&self, position: (f32, f32)) -> (f32, f32) {
+        self.window_to_ndc(self.canvas_to_window(position))
+    }
+
+    // Scale (xy) and translation (zw) taking canvas pixels to NDC, as used by the shaders
+    pub fn canvas_to_ndc_transform(&self) -> [f32; 4] {
+        let origin = self.canvas_to_ndc((0.0, 0.0));
+        let scale = self.scale();
+        [
+            2.0 * scale / self.window_size.0,
+            -2.0 * scale / self.window_size.1,
+            origin.0,
+            origin.1,
+        ]
+    }
+
+    // Scale (xy) and translation (zw) taking the full screen quad, which spans -1..1, to the area covered by the canvas
+    pub fn quad_transform(&self) -> [f32; 4] {
+        let top_left = self.canvas_to_ndc((0.0, 0.0));
+        let bottom_right = self.canvas_to_ndc(self.canvas_size);
+        [
+            (bottom_right.0 - top_left.0) / 2.0,
+            (top_left.1 - bottom_right.1) / 2.0,
+            (top_left.0 + bottom_right.0) / 2.0,
+            (top_left.1 + bottom_right.1) / 2.0,
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(actual: (f32, f32), expected: (f32, f32)) {
+        assert!(
+            (actual.0 - expected.0).abs() < 1e-4 && (actual.1 - expected.1).abs() < 1e-4,
+            "{:?} != {:?}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn matching_sizes_are_identity() {
+        let viewport = Viewport::new(PhysicalSize::new(800, 600), PhysicalSize::new(800, 600));
+        assert_eq!(viewport.scale(), 1.0);
+        assert_close(viewport.offset(), (0.0, 0.0));
+        assert_close(viewport.window_to_canvas((123.0, 456.0)), (123.0, 456.0));
+        assert_eq!(viewport.quad_transform(), [1.0, 1.0, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn hidpi_window_scales_pointer() {
+        // An 800x600 canvas on a screen with a scale factor of 2
+        let viewport = Viewport::new(PhysicalSize::new(1600, 1200), PhysicalSize::new(800, 600));
+        assert_eq!(viewport.scale(), 2.0);
+        assert_close(viewport.window_to_canvas((200.0, 300.0)), (100.0, 150.0));
+        assert_close(viewport.window_to_canvas((1600.0, 1200.0)), (800.0, 600.0));
+    }
+
+    #[test]
+    fn wide_window_is_letterboxed_on_the_sides() {
+        let viewport = Viewport::new(PhysicalSize::new(1000, 600), PhysicalSize::new(800, 600));
+        assert_eq!(viewport.scale(), 1.0);
+        assert_close(viewport.offset(), (100.0, 0.0));
+        assert_close(viewport.window_to_canvas((100.0, 0.0)), (0.0, 0.0));
+        assert_close(viewport.window_to_canvas((50.0, 10.0)), (-50.0, 10.0));
+        assert_close(viewport.canvas_to_ndc((0.0, 0.0)), (-0.8, 1.0));
+        assert_close(viewport.canvas_to_ndc((800.0, 600.0)), (0.8, -1.0));
+    }
+
+    #[test]
+    fn tall_window_is_letterboxed_top_and_bottom() {
+        let viewport = Viewport::new(PhysicalSize::new(400, 600), PhysicalSize::new(800, 600));
+        assert_eq!(viewport.scale(), 0.5);
+        assert_close(viewport.offset(), (0.0, 150.0));
+        assert_close(viewport.window_to_canvas((200.0, 300.0)), (400.0, 300.0));
+    }
+
+    #[test]
+    fn window_and_canvas_round_trip() {
+        let viewport = Viewport::new(PhysicalSize::new(1333, 777), PhysicalSize::new(600, 800));
+        for position in [(0.0, 0.0), (12.5, 700.0), (600.0, 800.0), (-10.0, 900.0)] {
+            assert_close(
+                viewport.window_to_canvas(viewport.canvas_to_window(position)),
+                position,
+            );
+        }
+    }
+
+    #[test]
+    fn zoom_keeps_the_point_under_the_cursor() {
+        let window = PhysicalSize::new(1000, 600);
+        let canvas = PhysicalSize::new(800, 600);
+        let viewport = Viewport::new(window, canvas);
+        let cursor = (300.0, 200.0);
+        let before = viewport.window_to_canvas(cursor);
+        let zoomed = Viewport::with_view(window, canvas, viewport.zoom_at(cursor, 4.0));
+        assert_eq!(zoomed.scale(), 4.0);
+        assert_close(zoomed.window_to_canvas(cursor), before);
+        let unzoomed = Viewport::with_view(window, canvas, zoomed.zoom_at(cursor, 0.25));
+        assert_close(unzoomed.offset(), viewport.offset());
+    }
+
+    #[test]
+    fn zoom_is_clamped() {
+        let viewport = Viewport::new(PhysicalSize::new(800, 600), PhysicalSize::new(800, 600));
+        assert_eq!(
+            viewport.zoom_at((0.0, 0.0), 1000.0).zoom,
+            ViewTransform::MAX_ZOOM
+        );
+        assert_eq!(
+            viewport.zoom_at((0.0, 0.0), 0.0).zoom,
+            ViewTransform::MIN_ZOOM
+        );
+    }
+
+    #[test]
+    fn pan_moves_the_canvas_under_the_pointer() {
+        let window = PhysicalSize::new(800, 600);
+        let canvas = PhysicalSize::new(800, 600);
+        let view = ViewTransform {
+            zoom: 2.0,
+            pan: (0.0, 0.0),
+        };
+        let viewport = Viewport::with_view(window, canvas, view);
+        let panned = Viewport::with_view(window, canvas, view.panned((50.0, -20.0)));
+        let point = viewport.window_to_canvas((400.0, 300.0));
+        assert_close(point, (400.0, 300.0));
+        assert_close(panned.canvas_to_window(point), (450.0, 280.0));
+        assert_close(panned.window_to_canvas((450.0, 280.0)), point);
+    }
+
+    #[test]
+    fn shader_transforms_match_canvas_to_ndc() {
+        let view = ViewTransform {
+            zoom: 1.5,
+            pan: (-40.0, 25.0),
+        };
+        let viewport = Viewport::with_view(
+            PhysicalSize::new(1280, 720),
+            PhysicalSize::new(600, 600),
+            view,
+        );
+        let [sx, sy, tx, ty] = viewport.canvas_to_ndc_transform();
+        for position in [(0.0, 0.0), (600.0, 600.0), (150.0, 420.0)] {
+            assert_close(
+                (position.0 * sx + tx, position.1 * sy + ty),
+                viewport.canvas_to_ndc(position),
+            );
+        }
+        let [sx, sy, tx, ty] = viewport.quad_transform();
+        assert_close((-sx + tx, sy + ty), viewport.canvas_to_ndc((0.0, 0.0)));
+        assert_close((sx + tx, -sy + ty), viewport.canvas_to_ndc((600.0, 600.0)));
+    }
+}
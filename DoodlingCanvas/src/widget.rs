@@ -0,0 +1,451 @@
+// The canvas widget handed to the page (or the desktop binary), it runs in the shared event loop of `host`
+use std::sync::{Arc, Mutex, Once};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use image::EncodableLayout;
+use log::info;
+#[cfg(target_arch = "wasm32")]
+use wasm_bindgen::prelude::*;
+
+use crate::{
+    callbacks::{self, Callbacks},
+    color,
+    document::StrokeDocument,
+    export::{self, ExportOptions},
+    host::{self, WidgetProxy},
+    import::{self, ImageFit, ImageImport},
+    layers::{Background, BlendMode, LayerCommand},
+    options::WidgetOptions,
+    render_state::GpuContext,
+    stabilizer::StabilizerMode,
+    stroke::PressureCurve,
+    svg,
+    tools::{Tool, ToolCommand},
+    utils,
+    winit_app::{CanvasApp, DocumentSlot, Events, GetFramebufferAction},
+};
+
+#[cfg_attr(target_arch = "wasm32", wasm_bindgen)]
+pub struct WindowHandler {
+    // Handed over to the shared event loop by `run_window_loop`
+    app: Arc<Mutex<Option<CanvasApp>>>,
+    event_loop_proxy: Arc<Mutex<WidgetProxy>>,
+    get_framebuffer: GetFramebufferAction,
+    document: DocumentSlot,
+    callbacks: Callbacks,
+}
+
+#[cfg_attr(target_arch = "wasm32", wasm_bindgen)]
+impl WindowHandler {
+    #[cfg_attr(target_arch = "wasm32", wasm_bindgen)]
+    pub fn new(other: &WindowHandler) -> Self {
+        Self {
+            app: other.app.clone(),
+            event_loop_proxy: other.event_loop_proxy.clone(),
+            get_framebuffer: other.get_framebuffer.clone(),
+            document: other.document.clone(),
+            callbacks: other.callbacks.clone(),
+        }
+    }
+
+    // Every widget of the page runs in the same event loop, the first one to run starts it.
+    // On the desktop this returns once the window is closed
+    #[cfg_attr(target_arch = "wasm32", wasm_bindgen)]
+    pub fn run_window_loop(self) {
+        let Some(app) = self.app.lock().unwrap().take() else {
+            log::warn!("The window loop is already running");
+            return;
+        };
+        let id = self.event_loop_proxy.lock().unwrap().id();
+        info!("Running widget {}", id);
+        host::run(id, app);
+    }
+
+    #[cfg_attr(target_arch = "wasm32", wasm_bindgen)]
+    pub async fn get_canvas_capture(&self) -> String {
+        match self.capture().await {
+            Ok((img, _)) => {
+                let buffer = color::encode_png(&img).unwrap();
+                STANDARD.encode(buffer.as_bytes())
+            }
+            Err(err) => {
+                log::warn!("No canvas capture: {:#}", err);
+                "".to_owned()
+            }
+        }
+    }
+
+    // The recorded strokes as a stroke document (JSON), empty until the canvas is ready. Also empty
+    // once fills, shapes or images changed the canvas, the strokes alone aren't the doodle then
+    #[cfg_attr(target_arch = "wasm32", wasm_bindgen)]
+    pub fn get_stroke_document(&self) -> String {
+        self.document
+            .lock()
+            .unwrap()
+            .as_ref()
+            .filter(|document| !document.incomplete)
+            .map_or_else(String::new, StrokeDocument::to_json)
+    }
+
+    // The recorded strokes as an SVG document, empty until the canvas is ready or when the strokes
+    // aren't the whole doodle
+    #[cfg_attr(target_arch = "wasm32", wasm_bindgen)]
+    pub fn export_svg(&self) -> String {
+        let document = self.document.lock().unwrap().clone();
+        match document.map(|document| svg::to_svg(&document)) {
+            Some(Ok(svg)) => svg,
+            Some(Err(err)) => {
+                self.callbacks
+                    .report_error(format!("Failed to export the strokes: {:#}", err));
+                String::new()
+            }
+            None => String::new(),
+        }
+    }
+
+    #[cfg_attr(target_arch = "wasm32", wasm_bindgen)]
+    pub fn close(&self) {
+        info!("Sending close event");
+        self.send_event(Events::Close);
+    }
+
+    // Layers are addressed by their index in the stack, 0 being the bottom layer
+    #[cfg_attr(target_arch = "wasm32", wasm_bindgen)]
+    pub fn add_layer(&self) {
+        self.send_event(Events::Layer(LayerCommand::Add));
+    }
+
+    #[cfg_attr(target_arch = "wasm32", wasm_bindgen)]
+    pub fn remove_layer(&self, index: usize) {
+        self.send_event(Events::Layer(LayerCommand::Remove(index)));
+    }
+
+    #[cfg_attr(target_arch = "wasm32", wasm_bindgen)]
+    pub fn move_layer(&self, from: usize, to: usize) {
+        self.send_event(Events::Layer(LayerCommand::Move { from, to }));
+    }
+
+    #[cfg_attr(target_arch = "wasm32", wasm_bindgen)]
+    pub fn select_layer(&self, index: usize) {
+        self.send_event(Events::Layer(LayerCommand::Select(index)));
+    }
+
+    #[cfg_attr(target_arch = "wasm32", wasm_bindgen)]
+    pub fn set_layer_opacity(&self, index: usize, opacity: f32) {
+        self.send_event(Events::Layer(LayerCommand::SetOpacity(index, opacity)));
+    }
+
+    #[cfg_attr(target_arch = "wasm32", wasm_bindgen)]
+    pub fn set_layer_visible(&self, index: usize, visible: bool) {
+        self.send_event(Events::Layer(LayerCommand::SetVisible(index, visible)));
+    }
+
+    #[cfg_attr(target_arch = "wasm32", wasm_bindgen)]
+    pub fn set_layer_blend_mode(&self, index: usize, blend_mode: BlendMode) {
+        self.send_event(Events::Layer(LayerCommand::SetBlendMode(index, blend_mode)));
+    }
+
+    #[cfg_attr(target_arch = "wasm32", wasm_bindgen)]
+    pub fn set_tool(&self, tool: Tool) {
+        self.send_event(Events::Tool(ToolCommand::SetTool(tool)));
+    }
+
+    // The color is sRGB with straight alpha
+    #[cfg_attr(target_arch = "wasm32", wasm_bindgen)]
+    pub fn set_color(&self, r: u8, g: u8, b: u8, a: u8) {
+        self.send_event(Events::Tool(ToolCommand::SetColor(image::Rgba([
+            r, g, b, a,
+        ]))));
+    }
+
+    // Brush size and shape outline thickness, in canvas pixels
+    #[cfg_attr(target_arch = "wasm32", wasm_bindgen)]
+    pub fn set_brush_size(&self, size: f32) {
+        self.send_event(Events::Tool(ToolCommand::SetSize(size)));
+    }
+
+    #[cfg_attr(target_arch = "wasm32", wasm_bindgen)]
+    pub fn set_shape_filled(&self, filled: bool) {
+        self.send_event(Events::Tool(ToolCommand::SetShapeFilled(filled)));
+    }
+
+    #[cfg_attr(target_arch = "wasm32", wasm_bindgen)]
+    pub fn set_fill_tolerance(&self, tolerance: u8) {
+        self.send_event(Events::Tool(ToolCommand::SetFillTolerance(tolerance)));
+    }
+
+    // Exponent applied to pen pressure, 1 is linear
+    #[cfg_attr(target_arch = "wasm32", wasm_bindgen)]
+    pub fn set_pressure_curve(&self, exponent: f32) {
+        self.send_event(Events::Tool(ToolCommand::SetPressureCurve(PressureCurve {
+            exponent: exponent.max(0.01),
+        })));
+    }
+
+    #[cfg_attr(target_arch = "wasm32", wasm_bindgen)]
+    pub fn set_stabilizer_mode(&self, mode: StabilizerMode) {
+        self.send_event(Events::Tool(ToolCommand::SetStabilizerMode(mode)));
+    }
+
+    // From 0, no smoothing, to 1
+    #[cfg_attr(target_arch = "wasm32", wasm_bindgen)]
+    pub fn set_stabilizer_strength(&self, strength: f32) {
+        self.send_event(Events::Tool(ToolCommand::SetStabilizerStrength(strength)));
+    }
+
+    // The callback receives the picked color as a "#rrggbbaa" string
+    #[cfg(target_arch = "wasm32")]
+    #[cfg_attr(target_arch = "wasm32", wasm_bindgen)]
+    pub fn set_color_picked_callback(&self, callback: js_sys::Function) {
+        self.set_color_picked_handler(move |color| {
+            let _ = callback.call1(&JsValue::NULL, &JsValue::from(color::to_hex(color)));
+        });
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    #[cfg_attr(target_arch = "wasm32", wasm_bindgen)]
+    pub fn set_stroke_start_callback(&self, callback: js_sys::Function) {
+        self.set_stroke_start_handler(move || {
+            let _ = callback.call0(&JsValue::NULL);
+        });
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    #[cfg_attr(target_arch = "wasm32", wasm_bindgen)]
+    pub fn set_stroke_end_callback(&self, callback: js_sys::Function) {
+        self.set_stroke_end_handler(move || {
+            let _ = callback.call0(&JsValue::NULL);
+        });
+    }
+
+    // Called after every finished edit, e.g. to warn about unsaved changes
+    #[cfg(target_arch = "wasm32")]
+    #[cfg_attr(target_arch = "wasm32", wasm_bindgen)]
+    pub fn set_canvas_changed_callback(&self, callback: js_sys::Function) {
+        self.set_canvas_changed_handler(move || {
+            let _ = callback.call0(&JsValue::NULL);
+        });
+    }
+
+    // The callback receives the new `Tool`
+    #[cfg(target_arch = "wasm32")]
+    #[cfg_attr(target_arch = "wasm32", wasm_bindgen)]
+    pub fn set_tool_changed_callback(&self, callback: js_sys::Function) {
+        self.set_tool_changed_handler(move |tool| {
+            let _ = callback.call1(&JsValue::NULL, &JsValue::from(tool));
+        });
+    }
+
+    // The callback receives a message that can be shown to the user
+    #[cfg(target_arch = "wasm32")]
+    #[cfg_attr(target_arch = "wasm32", wasm_bindgen)]
+    pub fn set_error_callback(&self, callback: js_sys::Function) {
+        self.set_error_handler(move |message| {
+            let _ = callback.call1(&JsValue::NULL, &JsValue::from(message));
+        });
+    }
+
+    #[cfg_attr(target_arch = "wasm32", wasm_bindgen)]
+    pub fn undo(&self) {
+        self.send_event(Events::Undo);
+    }
+
+    // Resets zoom and pan so the whole canvas is visible
+    #[cfg_attr(target_arch = "wasm32", wasm_bindgen)]
+    pub fn fit_to_window(&self) {
+        self.send_event(Events::FitToWindow);
+    }
+
+    // Replaces the active layer with a PNG or JPEG image, it can be undone like any edit
+    #[cfg(target_arch = "wasm32")]
+    #[cfg_attr(target_arch = "wasm32", wasm_bindgen)]
+    pub fn load_image(&self, bytes: &[u8], fit: ImageFit) -> Result<(), JsError> {
+        let image = import::decode(bytes).map_err(|err| JsError::new(&format!("{:#}", err)))?;
+        self.send_event(Events::LoadImage(ImageImport { image, fit }));
+        Ok(())
+    }
+
+    // "transparent" or a "#rrggbb" color, a transparent background is exported with its alpha
+    #[cfg(target_arch = "wasm32")]
+    #[cfg_attr(target_arch = "wasm32", wasm_bindgen)]
+    pub fn set_background(&self, background: &str) -> Result<(), JsError> {
+        let background = background
+            .parse()
+            .map_err(|err: String| JsError::new(&err))?;
+        self.send_event(Events::Background(background));
+        Ok(())
+    }
+
+    // The canvas as a file, see `ExportOptions::from_js` for the options. The bytes can be
+    // wrapped in a Blob to download them or upload them in a form
+    #[cfg(target_arch = "wasm32")]
+    #[cfg_attr(target_arch = "wasm32", wasm_bindgen)]
+    pub async fn export(&self, options: JsValue) -> Result<js_sys::Uint8Array, JsError> {
+        let options = ExportOptions::from_js(&options);
+        let (image, background) = self
+            .capture()
+            .await
+            .map_err(|err| JsError::new(&format!("{:#}", err)))?;
+        let bytes = export::encode(image, background, &options)
+            .map_err(|err| JsError::new(&format!("{:#}", err)))?;
+        Ok(js_sys::Uint8Array::from(bytes.as_slice()))
+    }
+
+    // The exported canvas and its background, an error until the canvas is ready
+    async fn capture(&self) -> anyhow::Result<(image::RgbaImage, Background)> {
+        // The lock must not be held while the capture is awaited
+        let capture = self
+            .get_framebuffer
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|get_frame| get_frame());
+        capture
+            .ok_or_else(|| anyhow::anyhow!("The canvas isn't ready yet"))?
+            .await
+    }
+
+    fn send_event(&self, event: Events) {
+        self.event_loop_proxy
+            .lock()
+            .unwrap()
+            .send_event(event)
+            .expect("Failed to send event to the event loop");
+    }
+}
+
+// Rust only counterparts of the callbacks given to the web page
+impl WindowHandler {
+    pub fn set_color_picked_handler(&self, handler: impl Fn(image::Rgba<u8>) + 'static) {
+        callbacks::set(&self.callbacks.color_picked, handler);
+    }
+
+    pub fn set_stroke_start_handler(&self, handler: impl Fn() + 'static) {
+        callbacks::set(&self.callbacks.stroke_started, move |()| handler());
+    }
+
+    pub fn set_stroke_end_handler(&self, handler: impl Fn() + 'static) {
+        callbacks::set(&self.callbacks.stroke_ended, move |()| handler());
+    }
+
+    pub fn set_canvas_changed_handler(&self, handler: impl Fn() + 'static) {
+        callbacks::set(&self.callbacks.canvas_changed, move |()| handler());
+    }
+
+    pub fn set_tool_changed_handler(&self, handler: impl Fn(Tool) + 'static) {
+        callbacks::set(&self.callbacks.tool_changed, handler);
+    }
+
+    pub fn set_error_handler(&self, handler: impl Fn(String) + 'static) {
+        callbacks::set(&self.callbacks.error, handler);
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl WindowHandler {
+    // Replaces the active layer with a PNG or JPEG image, it can be undone like any edit
+    pub fn load_image(&self, bytes: &[u8], fit: ImageFit) -> anyhow::Result<()> {
+        let image = import::decode(bytes)?;
+        self.send_event(Events::LoadImage(ImageImport { image, fit }));
+        Ok(())
+    }
+
+    // The canvas encoded as a file
+    pub async fn export(&self, options: ExportOptions) -> anyhow::Result<Vec<u8>> {
+        let (image, background) = self.capture().await?;
+        export::encode(image, background, &options)
+    }
+
+    // "transparent" or a "#rrggbb" color, a transparent background is exported with its alpha
+    pub fn set_background(&self, background: &str) -> anyhow::Result<()> {
+        let background = background.parse().map_err(anyhow::Error::msg)?;
+        self.send_event(Events::Background(background));
+        Ok(())
+    }
+
+    // The PNG file Ctrl+S writes the canvas to
+    pub fn set_output_path(&mut self, path: impl Into<std::path::PathBuf>) {
+        if let Some(app) = self.app.lock().unwrap().as_mut() {
+            app.set_output_path(path.into());
+        }
+    }
+}
+
+// Logging and the panic hook are global, they are only set up for the first widget
+fn init_logging() {
+    static INIT: Once = Once::new();
+    INIT.call_once(|| {
+        cfg_if::cfg_if! {
+        if #[cfg(target_arch = "wasm32")] {
+            std::panic::set_hook(Box::new(console_error_panic_hook::hook));
+            console_log::init_with_level(log::Level::Info).expect("Failed to initialize logger");
+        } else {
+            env_logger::init();
+        }
+        }
+    });
+}
+
+// Fails when no graphics backend works, the page can then tell the user instead of showing a dead canvas.
+// The options are described in `WidgetOptions::from_js`, the canvas size is clamped to
+// utils::MIN_CANVAS_SIZE..=utils::MAX_CANVAS_SIZE in both dimensions
+#[cfg(target_arch = "wasm32")]
+#[wasm_bindgen]
+pub async fn create_window(options: JsValue) -> Result<WindowHandler, JsError> {
+    init_logging();
+    let options =
+        WidgetOptions::from_js(&options).map_err(|err| JsError::new(&format!("{:#}", err)))?;
+    let gpu = GpuContext::negotiate(&options.canvas)
+        .await
+        .map_err(|err| JsError::new(&format!("{:#}", err)))?;
+    build_window_handler(options, gpu).map_err(|err| JsError::new(&format!("{:#}", err)))
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub fn create_window(options: WidgetOptions) -> anyhow::Result<WindowHandler> {
+    init_logging();
+    let gpu = pollster::block_on(GpuContext::negotiate())?;
+    build_window_handler(options, gpu)
+}
+
+// The handler is only ever used from the thread running the event loop
+#[allow(clippy::arc_with_non_send_sync)]
+fn build_window_handler(
+    mut options: WidgetOptions,
+    gpu: GpuContext,
+) -> anyhow::Result<WindowHandler> {
+    let (width, height) = (options.width, options.height);
+    (options.width, options.height) = utils::clamp_canvas_size(width, height);
+    if (options.width, options.height) != (width, height) {
+        log::warn!(
+            "Canvas size {}x{} is out of range, using {}x{}",
+            width,
+            height,
+            options.width,
+            options.height
+        );
+    }
+    info!("Creating window");
+    let event_loop_proxy = Arc::new(Mutex::new(host::widget_proxy()?));
+    let get_framebuffer: GetFramebufferAction = Arc::new(Mutex::new(None));
+    let document: DocumentSlot = Arc::new(Mutex::new(None));
+    let callbacks = Callbacks::default();
+    let app = CanvasApp::new(
+        event_loop_proxy.clone(),
+        get_framebuffer.clone(),
+        callbacks.clone(),
+        document.clone(),
+        options,
+        gpu,
+    );
+    Ok(WindowHandler {
+        app: Arc::new(Mutex::new(Some(app))),
+        event_loop_proxy,
+        get_framebuffer,
+        document,
+        callbacks,
+    })
+}
+
+#[cfg_attr(target_arch = "wasm32", wasm_bindgen(start))]
+fn _entry_point() {}
@@ -1,82 +1,667 @@
-use crate::{brush::Rectangle, render_state::State, utils};
+use crate::{
+    callbacks::{self, Callbacks},
+    color,
+    document::{StrokeData, StrokeDocument},
+    fill,
+    host::WidgetProxy,
+    import::{self, ImageImport},
+    layers::{Background, LayerCommand},
+    options::WidgetOptions,
+    render_state::{CanvasSnapshot, GpuContext, State, UndoKind},
+    shapes::Shape,
+    stabilizer::{Stabilizer, StabilizerMode},
+    stroke::{Stroke, StrokePoint},
+    tools::{Tool, ToolCommand, ToolSettings},
+    utils,
+    viewport::ViewTransform,
+};
 use log::info;
 use std::{
     future::Future,
     pin::Pin,
-    sync::{Arc, Mutex},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
 };
+use web_time::Instant;
 use winit::{
     application::ApplicationHandler,
     dpi::LogicalSize,
-    event::{ElementState, KeyEvent, MouseButton, WindowEvent},
-    event_loop::{ActiveEventLoop, EventLoopProxy},
-    keyboard::{Key, NamedKey},
-    window::Window,
+    dpi::PhysicalSize,
+    event::{ElementState, KeyEvent, MouseButton, MouseScrollDelta, TouchPhase, WindowEvent},
+    keyboard::{Key, ModifiersState, NamedKey},
+    window::{Window, WindowId},
 };
 #[derive(Debug)]
 pub enum Events {
     NewState(Arc<Mutex<State>>),
+    Layer(LayerCommand),
+    Tool(ToolCommand),
+    Undo,
+    FitToWindow,
+    LoadImage(ImageImport),
+    Background(Background),
     Close,
 }
+// Resolves to the exported canvas and the background it was drawn on
+pub type Capture = Pin<Box<dyn Future<Output = anyhow::Result<(image::RgbaImage, Background)>>>>;
 //maybe should just return
-pub type GetFramebufferAction =
-    Arc<Mutex<Option<Box<dyn Fn() -> Pin<Box<dyn Future<Output = image::RgbaImage>>>>>>>; //Look at this! This comment was made before adding Pin :(
+pub type GetFramebufferAction = Arc<Mutex<Option<Box<dyn Fn() -> Capture>>>>; //Look at this! This comment was made before adding Pin :(
+type SnapshotSlot = Arc<Mutex<SnapshotState>>;
+// The finished strokes with the layers and background they are drawn on, None until the canvas is ready
+pub type DocumentSlot = Arc<Mutex<Option<StrokeDocument>>>;
+
+// How long the canvas has to be left alone before it's copied for recovery. Reading every layer
+// back blocks the desktop loop, so it isn't done after each edit while the user keeps drawing
+const SNAPSHOT_DELAY: Duration = Duration::from_secs(2);
+
+// The canvas copied to recover from a lost device. Edits made after it was taken are lost with the device
+#[derive(Default)]
+struct SnapshotState {
+    canvas: Option<CanvasSnapshot>,
+    // When the canvas last changed, None if the copy is up to date
+    changed_at: Option<Instant>,
+}
+
+// Called after every finished edit
+fn edit_finished(slot: &SnapshotSlot, callbacks: &Callbacks) {
+    mark_snapshot_stale(slot);
+    callbacks::call(&callbacks.canvas_changed, ());
+}
+
+// The snapshot is refreshed later, see `CanvasApp::refresh_snapshot`
+fn mark_snapshot_stale(slot: &SnapshotSlot) {
+    slot.lock().unwrap().changed_at = Some(Instant::now());
+}
+
+// Like the capture, the state isn't locked while the layers are read back
+fn store_snapshot(state: &Arc<Mutex<State>>, slot: &SnapshotSlot) {
+    // Edits made during the readback make it stale again
+    slot.lock().unwrap().changed_at = None;
+    let readback = state.lock().unwrap().snapshot();
+    let slot = slot.clone();
+    utils::spawn(async move {
+        match readback.await {
+            Ok(snapshot) => {
+                slot.lock().unwrap().canvas = Some(snapshot);
+            }
+            // The previous snapshot is kept
+            Err(err) => log::warn!("Failed to snapshot the canvas: {:#}", err),
+        }
+    });
+}
+
+// The capture only locks the state to start the readback, not while waiting for it
+fn set_framebuffer_capture(get_framebuffer: &GetFramebufferAction, state: &Arc<Mutex<State>>) {
+    let state = state.clone();
+    get_framebuffer.lock().unwrap().replace(Box::new(move || {
+        let mut state = state.lock().unwrap();
+        let background = state.background();
+        let capture = state.export();
+        Box::pin(async move { Ok((capture.await?, background)) })
+    }));
+}
 
 #[allow(dead_code)]
 pub struct CanvasApp {
     mouse_pressed: bool,
+    // Last pointer position in window coordinates (physical pixels)
     mouse_position: (f32, f32),
+    // Pressure of the last pointer sample, 1 when the device doesn't report any
+    pressure: f32,
+    // Pen pressure read from the browser's pointer events, winit doesn't expose it for pens
+    pen_pressure: Arc<Mutex<Option<f32>>>,
+    // The finger that is drawing, other touches are ignored until it's lifted
+    touch_id: Option<u64>,
+    modifiers: ModifiersState,
+    space_pressed: bool,
+    // Set while the view is being dragged with space + left button or the middle button
+    panning: bool,
+    tool_settings: ToolSettings,
+    // Where the shape currently being dragged out was started
+    shape_start: Option<(f32, f32)>,
+    // The brush stroke being drawn and the finished ones, kept for replay
+    stroke: Option<Stroke>,
+    strokes: Vec<Stroke>,
+    stabilizer: Stabilizer,
     window: Option<Arc<Window>>,
     pub state: Option<Arc<Mutex<State>>>,
-    event_loop: Arc<Mutex<EventLoopProxy<Events>>>,
+    event_loop: Arc<Mutex<WidgetProxy>>,
     get_framebuffer: GetFramebufferAction,
+    callbacks: Callbacks,
+    canvas_size: PhysicalSize<u32>,
+    // The pointer only pans the view, nothing can be drawn
+    read_only: bool,
+    // Taken when the window is created
+    gpu: Option<GpuContext>,
+    // Loaded once the state exists, without an undo step
+    initial_image: Option<ImageImport>,
+    // Kept here as well so a recreated state gets it back. Changing it can't be undone
+    background: Background,
+    #[cfg(target_arch = "wasm32")]
+    canvas: Option<web_sys::HtmlCanvasElement>,
+    // The canvas after an earlier edit, to recover from a lost device
+    snapshot: SnapshotSlot,
+    // Updated whenever a stroke is finished or the layers change
+    document: DocumentSlot,
+    // Fills, shapes and imported images on the canvas that haven't been undone. They aren't recorded,
+    // so the document is incomplete while there are any
+    paint_edits: Arc<AtomicUsize>,
+    // Where Ctrl+S writes the canvas
+    #[cfg(not(target_arch = "wasm32"))]
+    output_path: std::path::PathBuf,
 }
 
 impl CanvasApp {
+    pub fn strokes(&self) -> &[Stroke] {
+        &self.strokes
+    }
     pub fn renderer(&self) -> Arc<Mutex<State>> {
         self.state.as_ref().unwrap().clone()
     }
+    pub fn window_id(&self) -> Option<WindowId> {
+        self.window.as_ref().map(|window| window.id())
+    }
     pub fn new(
-        event_loop: Arc<Mutex<EventLoopProxy<Events>>>,
+        event_loop: Arc<Mutex<WidgetProxy>>,
         get_framebuffer: GetFramebufferAction,
+        callbacks: Callbacks,
+        document: DocumentSlot,
+        options: WidgetOptions,
+        gpu: GpuContext,
     ) -> Self {
         Self {
             mouse_pressed: false,
             mouse_position: (0.0, 0.0),
+            pressure: 1.0,
+            pen_pressure: Arc::new(Mutex::new(None)),
+            touch_id: None,
+            modifiers: ModifiersState::empty(),
+            space_pressed: false,
+            panning: false,
+            tool_settings: ToolSettings::default(),
+            shape_start: None,
+            stroke: None,
+            strokes: Vec::new(),
+            stabilizer: Stabilizer::new(StabilizerMode::Off, 0.0),
             window: None,
             state: None,
             event_loop,
             get_framebuffer,
+            callbacks,
+            canvas_size: PhysicalSize::new(options.width, options.height),
+            read_only: options.read_only,
+            gpu: Some(gpu),
+            initial_image: options.image.map(|image| ImageImport {
+                image,
+                fit: options.image_fit,
+            }),
+            background: options.background,
+            #[cfg(target_arch = "wasm32")]
+            canvas: Some(options.canvas),
+            snapshot: Arc::new(Mutex::new(SnapshotState::default())),
+            document,
+            paint_edits: Arc::new(AtomicUsize::new(0)),
+            #[cfg(not(target_arch = "wasm32"))]
+            output_path: utils::DEFAULT_OUTPUT_PATH.into(),
+        }
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn set_output_path(&mut self, path: std::path::PathBuf) {
+        self.output_path = path;
+    }
+
+    // The pointer position in canvas pixels
+    fn canvas_position(&self) -> (f32, f32) {
+        self.renderer()
+            .lock()
+            .unwrap()
+            .viewport()
+            .window_to_canvas(self.mouse_position)
+    }
+
+    // The canvas pixel under the pointer, None over the letterbox around the canvas
+    fn canvas_pixel(&self) -> Option<(u32, u32)> {
+        let (x, y) = self.canvas_position();
+        let (width, height) = (
+            self.canvas_size.width as f32,
+            self.canvas_size.height as f32,
+        );
+        if x < 0.0 || y < 0.0 || x >= width || y >= height {
+            return None;
+        }
+        Some((x as u32, y as u32))
+    }
+
+    fn current_shape(&self) -> Option<Shape> {
+        let start = self.shape_start?;
+        let end = self.canvas_position();
+        let shape = Shape {
+            kind: self.tool_settings.tool.shape_kind()?,
+            start: [start.0, start.1],
+            end: [end.0, end.1],
+            filled: self.tool_settings.shape_filled,
+            thickness: self.tool_settings.size,
+            color: self.tool_settings.color,
+        };
+        if self.modifiers.shift_key() {
+            Some(shape.constrained())
+        } else {
+            Some(shape)
+        }
+    }
+
+    // Reads the active layer back, fills it on the CPU and uploads the result
+    fn fill_at_cursor(&self) {
+        let Some(position) = self.canvas_pixel() else {
+            return;
+        };
+        let state = self.renderer();
+        let color = color::to_layer_pixel(self.tool_settings.color);
+        let tolerance = self.tool_settings.fill_tolerance;
+        let (layer, readback) = {
+            let mut state = state.lock().unwrap();
+            let layer = state.active_layer();
+            (layer, state.read_layer(layer))
+        };
+        let snapshot = self.snapshot.clone();
+        let callbacks = self.callbacks.clone();
+        let document = self.document.clone();
+        let paint_edits = self.paint_edits.clone();
+        utils::spawn(async move {
+            let mut image = match readback.await {
+                Ok(image) => image,
+                Err(err) => {
+                    callbacks.report_error(format!("Failed to fill: {:#}", err));
+                    return;
+                }
+            };
+            if fill::flood_fill(&mut image, position, color, tolerance) {
+                {
+                    let mut state = state.lock().unwrap();
+                    state.push_undo(layer, UndoKind::Paint);
+                    state.write_layer(layer, &image);
+                }
+                paint_edits.fetch_add(1, Ordering::Relaxed);
+                if let Some(document) = document.lock().unwrap().as_mut() {
+                    document.incomplete = true;
+                }
+                edit_finished(&snapshot, &callbacks);
+            }
+        });
+    }
+
+    // Samples the flattened canvas under the cursor and makes it the active color
+    fn pick_color_at_cursor(&self) {
+        let Some((x, y)) = self.canvas_pixel() else {
+            return;
+        };
+        let flattened = self.renderer().lock().unwrap().flatten();
+        let event_loop = self.event_loop.clone();
+        let callbacks = self.callbacks.clone();
+        utils::spawn(async move {
+            let image = match flattened.await {
+                Ok(image) => image,
+                Err(err) => {
+                    callbacks.report_error(format!("Failed to pick a color: {:#}", err));
+                    return;
+                }
+            };
+            let Some(pixel) = image.get_pixel_checked(x, y) else {
+                return;
+            };
+            let color = color::from_layer_pixel(*pixel);
+            info!("Picked color {}", color::to_hex(color));
+            callbacks::call(&callbacks.color_picked, color);
+            // The loop may be gone by now, panicking here would take every widget of the page with it
+            let sent = event_loop
+                .lock()
+                .unwrap()
+                .send_event(Events::Tool(ToolCommand::SetColor(color)));
+            if let Err(err) = sent {
+                log::warn!("Failed to send the picked color: {}", err);
+            }
+        });
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    fn save_canvas(&self) {
+        let path = self.output_path.clone();
+        let exported = self.renderer().lock().unwrap().export();
+        let callbacks = self.callbacks.clone();
+        utils::spawn(async move {
+            let saved = async {
+                let png = color::encode_png(&exported.await?)?;
+                Ok::<_, anyhow::Error>(std::fs::write(&path, png)?)
+            };
+            let saved = saved.await;
+            match saved {
+                Ok(()) => info!("Saved canvas to {}", path.display()),
+                Err(err) => callbacks.report_error(format!(
+                    "Failed to save canvas to {}: {:#}",
+                    path.display(),
+                    err
+                )),
+            }
+        });
+    }
+
+    // Replaces the active layer with the image
+    fn load_image(&self, import: &ImageImport, undoable: bool) {
+        let mut image = import::fit_to_canvas(
+            &import.image,
+            (self.canvas_size.width, self.canvas_size.height),
+            import.fit,
+        );
+        for pixel in image.pixels_mut() {
+            *pixel = color::to_layer_pixel(*pixel);
+        }
+        let state = self.renderer();
+        {
+            let mut state = state.lock().unwrap();
+            let layer = state.active_layer();
+            if undoable {
+                state.push_undo(layer, UndoKind::Paint);
+            }
+            state.write_layer(layer, &image);
+        }
+        if undoable {
+            edit_finished(&self.snapshot, &self.callbacks);
+        } else {
+            // Not an edit, but a lost device should bring the image back
+            mark_snapshot_stale(&self.snapshot);
         }
+        // Without an undo step the image stays part of the canvas for good
+        self.paint_edits.fetch_add(1, Ordering::Relaxed);
+        self.update_document();
+    }
+
+    fn undo(&mut self) {
+        if self.read_only {
+            return;
+        }
+        // A stroke in progress is finished first so it's the one taken back
+        self.finish_stroke();
+        let Some(kind) = self.renderer().lock().unwrap().undo() else {
+            info!("Nothing to undo");
+            return;
+        };
+        edit_finished(&self.snapshot, &self.callbacks);
+        match kind {
+            // Strokes are undone newest first, so the undone one is the last recorded
+            UndoKind::Stroke => {
+                self.strokes.pop();
+            }
+            UndoKind::Paint => {
+                self.paint_edits.fetch_sub(1, Ordering::Relaxed);
+            }
+        }
+        self.update_document();
+    }
+
+    // Left button or touch, pressed or released
+    fn pointer_pressed(&mut self, pressed: bool) {
+        self.mouse_pressed = pressed;
+        if self.read_only {
+            self.panning = pressed;
+            return;
+        }
+        if self.tool_settings.tool == Tool::Brush {
+            if pressed {
+                // The whole stroke is undone at once
+                let rendptr = self.renderer();
+                let mut renderer = rendptr.lock().unwrap();
+                let layer = renderer.active_layer();
+                renderer.push_undo(layer, UndoKind::Stroke);
+                drop(renderer);
+                self.stroke = Some(Stroke::new(
+                    layer,
+                    self.tool_settings.color,
+                    self.tool_settings.size,
+                    self.tool_settings.pressure_curve,
+                ));
+                self.stabilizer = Stabilizer::new(
+                    self.tool_settings.stabilizer_mode,
+                    self.tool_settings.stabilizer_strength,
+                );
+                callbacks::call(&self.callbacks.stroke_started, ());
+                self.add_stroke_sample();
+            } else {
+                let held_back = self.stabilizer.finish();
+                self.draw_stroke_points(held_back);
+                self.finish_stroke();
+            }
+        } else if pressed && self.tool_settings.tool == Tool::Fill {
+            self.fill_at_cursor();
+        } else if pressed && self.tool_settings.tool == Tool::Eyedropper {
+            self.pick_color_at_cursor();
+        } else if self.tool_settings.tool.shape_kind().is_some() {
+            if pressed {
+                self.shape_start = Some(self.canvas_position());
+                self.update_shape_preview();
+            } else if let Some(shape) = self.current_shape() {
+                // Releasing the button commits the shape to the active layer
+                let rendptr = self.renderer();
+                let mut renderer = rendptr.lock().unwrap();
+                let layer = renderer.active_layer();
+                renderer.push_undo(layer, UndoKind::Paint);
+                let mut paint = renderer.begin_render();
+                renderer.draw_shape(&mut paint, &shape);
+                renderer.end_render(paint);
+                renderer.set_shape_preview(None);
+                drop(renderer);
+                edit_finished(&self.snapshot, &self.callbacks);
+                self.paint_edits.fetch_add(1, Ordering::Relaxed);
+                self.update_document();
+                self.shape_start = None;
+            }
+        }
+    }
+
+    // Feeds the pointer position to the stabilizer and draws what comes out of it
+    fn add_stroke_sample(&mut self) {
+        let (x, y) = self.canvas_position();
+        let points = self.stabilizer.push(StrokePoint {
+            x,
+            y,
+            pressure: self.pressure,
+        });
+        self.draw_stroke_points(points);
+    }
+
+    fn draw_stroke_points(&mut self, points: Vec<StrokePoint>) {
+        let rendptr = self.renderer();
+        let Some(stroke) = self.stroke.as_mut() else {
+            return;
+        };
+        let mut renderer = rendptr.lock().unwrap();
+        for point in points {
+            for dab in stroke.push(point) {
+                renderer.draw_dab(&dab);
+            }
+        }
+    }
+
+    fn finish_stroke(&mut self) {
+        if let Some(stroke) = self.stroke.take() {
+            callbacks::call(&self.callbacks.stroke_ended, ());
+            if stroke.points.is_empty() {
+                // Nothing was drawn, drop its undo step so undoing doesn't take back an earlier stroke
+                self.renderer().lock().unwrap().undo();
+            } else {
+                self.strokes.push(stroke);
+                edit_finished(&self.snapshot, &self.callbacks);
+                self.update_document();
+            }
+        }
+    }
+
+    // Pointer position in window coordinates (physical pixels)
+    fn pointer_moved(&mut self, position: (f32, f32)) {
+        let previous = self.mouse_position;
+        self.mouse_position = position;
+        if self.panning {
+            let rendptr = self.renderer();
+            let mut renderer = rendptr.lock().unwrap();
+            let view = renderer.view().panned((
+                self.mouse_position.0 - previous.0,
+                self.mouse_position.1 - previous.1,
+            ));
+            renderer.set_view(view);
+        }
+        if self.stroke.is_some() {
+            self.add_stroke_sample();
+        }
+        if self.shape_start.is_some() {
+            self.update_shape_preview();
+        }
+    }
+
+    fn mouse_pressure(&self) -> f32 {
+        self.pen_pressure.lock().unwrap().unwrap_or(1.0)
+    }
+
+    fn zoom_at_cursor(&self, factor: f32) {
+        let rendptr = self.renderer();
+        let mut renderer = rendptr.lock().unwrap();
+        let view = renderer.viewport().zoom_at(self.mouse_position, factor);
+        renderer.set_view(view);
+    }
+
+    // Copies the canvas once it hasn't changed for `SNAPSHOT_DELAY`, or right away when `now` is None.
+    // Returns when it should be called again, None while the snapshot is up to date
+    pub fn refresh_snapshot(&self, now: Option<Instant>) -> Option<Instant> {
+        let due = self.snapshot.lock().unwrap().changed_at? + SNAPSHOT_DELAY;
+        // Finishing the stroke in progress pushes the snapshot back anyway
+        if now.is_some() && self.stroke.is_some() {
+            return None;
+        }
+        if now.is_some_and(|now| now < due) {
+            return Some(due);
+        }
+        let state = self.state.clone()?;
+        if state.lock().unwrap().is_device_lost() {
+            return None;
+        }
+        store_snapshot(&state, &self.snapshot);
+        None
+    }
+
+    // The host removes the widget when it gets the event, see `close`
+    fn request_close(&self) {
+        self.event_loop
+            .lock()
+            .unwrap()
+            .send_event(Events::Close)
+            .expect("Failed to send close event");
+    }
+
+    // Drops the window and the renderer, captures fail from then on
+    pub fn close(&mut self) {
+        info!("Closing widget {}", self.event_loop.lock().unwrap().id());
+        self.get_framebuffer.lock().unwrap().take();
+        self.stroke = None;
+        self.state = None;
+        self.window = None;
+    }
+
+    // Builds a new state on a new device and puts back the canvas as it was after the last finished edit
+    fn recreate_state(&mut self) {
+        let Some(window) = self.window.clone() else {
+            return;
+        };
+        log::warn!("Recreating the renderer");
+        self.finish_stroke();
+        self.shape_start = None;
+        // The old surface has to be gone before the window gets a new one
+        self.state = None;
+        self.get_framebuffer.lock().unwrap().take();
+        let snapshot = self.snapshot.lock().unwrap().canvas.clone();
+        let event_loop = self.event_loop.clone();
+        let get_framebuffer = self.get_framebuffer.clone();
+        let callbacks = self.callbacks.clone();
+        let canvas_size = self.canvas_size;
+        utils::spawn(async move {
+            #[cfg(target_arch = "wasm32")]
+            let gpu = {
+                use winit::platform::web::WindowExtWebSys;
+                let canvas = window.canvas().expect("Failed to get canvas");
+                GpuContext::negotiate(&canvas).await
+            };
+            #[cfg(not(target_arch = "wasm32"))]
+            let gpu = GpuContext::negotiate().await;
+            let mut state = match gpu.and_then(|gpu| State::new(window, canvas_size, gpu)) {
+                Ok(state) => state,
+                Err(err) => {
+                    callbacks.report_error(format!("Failed to recreate the renderer: {:#}", err));
+                    return;
+                }
+            };
+            if let Some(snapshot) = snapshot {
+                state.restore(&snapshot);
+            }
+            let new_state = Arc::new(Mutex::new(state));
+            set_framebuffer_capture(&get_framebuffer, &new_state);
+            // The widget can be closed while the new device is set up
+            let sent = event_loop
+                .lock()
+                .unwrap()
+                .send_event(Events::NewState(new_state));
+            if let Err(err) = sent {
+                log::warn!("Failed to send the recreated renderer: {}", err);
+            }
+        });
+    }
+
+    fn update_document(&self) {
+        let Some(state) = &self.state else {
+            return;
+        };
+        let state = state.lock().unwrap();
+        let document = StrokeDocument {
+            width: self.canvas_size.width,
+            height: self.canvas_size.height,
+            layers: state.layer_settings(),
+            strokes: self.strokes.iter().map(StrokeData::from).collect(),
+            background: state.background(),
+            incomplete: self.paint_edits.load(Ordering::Relaxed) > 0,
+        };
+        self.document.lock().unwrap().replace(document);
+    }
+
+    fn update_shape_preview(&self) {
+        let shape = self.current_shape();
+        self.renderer()
+            .lock()
+            .unwrap()
+            .set_shape_preview(shape.as_ref());
     }
 }
 impl ApplicationHandler<Events> for CanvasApp {
     fn resumed(&mut self, event_loop: &winit::event_loop::ActiveEventLoop) {
-        let mut window_attributes = Window::default_attributes()
-            .with_max_inner_size(LogicalSize::new(utils::WINDOW_WIDTH, utils::WINDOW_HEIGHT));
+        // Only the first resume creates the window, the state is kept while suspended
+        let Some(gpu) = self.gpu.take() else {
+            return;
+        };
+        #[allow(unused_mut)]
+        // The canvas starts at its own size in logical pixels, resizing the window letterboxes it
+        let mut window_attributes = Window::default_attributes().with_inner_size(LogicalSize::new(
+            self.canvas_size.width,
+            self.canvas_size.height,
+        ));
 
         #[cfg(target_arch = "wasm32")]
         {
             info!("Initializing canvas");
-            use wasm_bindgen::JsCast;
-            use web_sys::HtmlCanvasElement;
             use winit::platform::web::WindowAttributesExtWebSys;
-            let window = web_sys::window().unwrap();
-            let document = window.document().unwrap();
-
-            let canvas = document.get_element_by_id("canvas").unwrap();
-            let html_canvas_element: HtmlCanvasElement = {
-                match canvas.dyn_into() {
-                    Ok(val) => val,
-                    Err(_) => {
-                        log::warn!("Failed to convert canvas to html canvas element");
-                        return;
-                    }
-                }
-            };
-            log::info!("Canvas created ({})", html_canvas_element.outer_html());
-
-            window_attributes = window_attributes.with_canvas(Some(html_canvas_element));
+            let canvas = self.canvas.take();
+            if let Some(canvas) = &canvas {
+                log::info!("Canvas created ({})", canvas.outer_html());
+            }
+            window_attributes = window_attributes.with_canvas(canvas);
         }
         let window = event_loop
             .create_window(window_attributes)
@@ -84,58 +669,57 @@ impl ApplicationHandler<Events> for CanvasApp {
 
         #[cfg(target_arch = "wasm32")]
         {
+            use wasm_bindgen::{closure::Closure, JsCast};
             use winit::platform::web::WindowExtWebSys;
             let canvas = window.canvas().expect("Failed to get canvas");
             log::info!("Window canvas size: ({})", canvas.outer_html());
+            // Capturing listeners run before winit's, so the pressure is up to date when it sends the event
+            let pen_pressure = self.pen_pressure.clone();
+            let on_pointer = Closure::<dyn FnMut(web_sys::PointerEvent)>::new(
+                move |event: web_sys::PointerEvent| {
+                    // Mice report 0.5 while a button is held, only pens have a real pressure
+                    *pen_pressure.lock().unwrap() =
+                        (event.pointer_type() == "pen").then(|| event.pressure());
+                },
+            );
+            for name in ["pointerdown", "pointermove"] {
+                canvas
+                    .add_event_listener_with_callback_and_bool(
+                        name,
+                        on_pointer.as_ref().unchecked_ref(),
+                        true,
+                    )
+                    .expect("Failed to listen to pointer events");
+            }
+            on_pointer.forget();
         }
         self.window = Some(Arc::new(window));
         log::info!(
             "Window created {:?}",
             self.window.as_ref().unwrap().inner_size()
         );
-        #[cfg(not(target_arch = "wasm32"))]
-        {
-            let new_state = Arc::new(Mutex::new(pollster::block_on(State::new(
-                self.window.as_ref().unwrap().clone(),
-            ))));
-            log::warn!("GetFramebuffer not implemented for non-wasm32");
-            self.event_loop
-                .lock()
-                .unwrap()
-                .send_event(Events::NewState(new_state))
-                .expect("Failed to send new state event");
-        }
-
-        #[cfg(target_arch = "wasm32")]
-        {
-            let window = self.window.as_ref().unwrap().clone();
-            let event_loop = self.event_loop.clone();
-            let get_framebuffer = self.get_framebuffer.clone();
-            wasm_bindgen_futures::spawn_local(async move {
-                let new_state = Arc::new(Mutex::new(State::new(window).await));
-                let fb_state = new_state.clone();
-                log::info!("Setting canvas capture");
-                get_framebuffer.lock().unwrap().replace(Box::new(move || {
-                    let fb_state = fb_state.clone();
-                    Box::pin(async move {
-                        let fb_state = fb_state.clone();
-                        let fb_state = fb_state.lock().unwrap();
-                        fb_state.extract_framebuffer().await
-                    })
-                }));
-
-                event_loop
-                    .lock()
-                    .unwrap()
-                    .send_event(Events::NewState(new_state))
-                    .expect("Failed to send new state event");
-            });
-        }
+        let new_state =
+            match State::new(self.window.as_ref().unwrap().clone(), self.canvas_size, gpu) {
+                Ok(state) => Arc::new(Mutex::new(state)),
+                Err(err) => {
+                    self.callbacks
+                        .report_error(format!("Failed to set up rendering: {:#}", err));
+                    // Other widgets of the page share the loop, only this one goes away
+                    self.request_close();
+                    return;
+                }
+            };
+        set_framebuffer_capture(&self.get_framebuffer, &new_state);
+        self.event_loop
+            .lock()
+            .unwrap()
+            .send_event(Events::NewState(new_state))
+            .expect("Failed to send new state event");
     }
 
     fn window_event(
         &mut self,
-        event_loop: &winit::event_loop::ActiveEventLoop,
+        _event_loop: &winit::event_loop::ActiveEventLoop,
         _window_id: winit::window::WindowId,
         event: winit::event::WindowEvent,
     ) {
@@ -143,11 +727,10 @@ impl ApplicationHandler<Events> for CanvasApp {
             log::warn!("Cannot process window events: state is none");
             return;
         }
-        let mut rect = {
-            let rendptr = self.renderer();
-            let mut state = rendptr.lock().unwrap();
-            Rectangle::new(&mut state, [0.0, 0.0, 10.0, 10.0])
-        };
+        if self.renderer().lock().unwrap().is_device_lost() {
+            self.recreate_state();
+            return;
+        }
         match event {
             WindowEvent::CloseRequested
             | WindowEvent::KeyboardInput {
@@ -159,16 +742,54 @@ impl ApplicationHandler<Events> for CanvasApp {
                     },
                 ..
             } => {
+                // On the web the page closes widgets through `WindowHandler::close`, Escape must not
+                // throw away the doodle
                 #[cfg(not(target_arch = "wasm32"))]
-                event_loop.exit();
+                self.request_close();
             }
 
             WindowEvent::MouseInput {
+                state,
+                button: MouseButton::Middle,
+                ..
+            } => {
+                self.panning = state == ElementState::Pressed;
+            }
+            WindowEvent::MouseInput {
+                state: ElementState::Pressed,
+                button: MouseButton::Left,
+                ..
+            } if self.space_pressed => {
+                self.panning = true;
+            }
+            WindowEvent::MouseInput {
+                state: ElementState::Released,
+                button: MouseButton::Left,
+                ..
+            } if self.panning => {
+                self.panning = false;
+            }
+            WindowEvent::MouseInput {
+                state,
                 button: MouseButton::Left,
                 ..
             } => {
-                if let WindowEvent::MouseInput { state: pressed, .. } = event {
-                    self.mouse_pressed = pressed == ElementState::Pressed;
+                self.pressure = self.mouse_pressure();
+                self.pointer_pressed(state == ElementState::Pressed);
+            }
+            WindowEvent::Touch(touch) if self.touch_id.map_or(true, |id| id == touch.id) => {
+                self.pressure = touch.force.map_or(1.0, |force| force.normalized() as f32);
+                self.pointer_moved((touch.location.x as f32, touch.location.y as f32));
+                match touch.phase {
+                    TouchPhase::Started => {
+                        self.touch_id = Some(touch.id);
+                        self.pointer_pressed(true);
+                    }
+                    TouchPhase::Ended | TouchPhase::Cancelled => {
+                        self.touch_id = None;
+                        self.pointer_pressed(false);
+                    }
+                    TouchPhase::Moved => {}
                 }
             }
             WindowEvent::MouseInput {
@@ -176,47 +797,112 @@ impl ApplicationHandler<Events> for CanvasApp {
                 button: MouseButton::Right,
                 ..
             } => {}
+            WindowEvent::KeyboardInput {
+                event:
+                    KeyEvent {
+                        state: ElementState::Pressed,
+                        logical_key: Key::Character(key),
+                        ..
+                    },
+                ..
+            } if key.as_str() == "z"
+                && (self.modifiers.control_key() || self.modifiers.super_key()) =>
+            {
+                self.undo();
+            }
+            #[cfg(not(target_arch = "wasm32"))]
+            WindowEvent::KeyboardInput {
+                event:
+                    KeyEvent {
+                        state: ElementState::Pressed,
+                        logical_key: Key::Character(key),
+                        ..
+                    },
+                ..
+            } if key.as_str() == "s"
+                && (self.modifiers.control_key() || self.modifiers.super_key()) =>
+            {
+                self.save_canvas();
+            }
+            WindowEvent::KeyboardInput {
+                event:
+                    KeyEvent {
+                        state: ElementState::Pressed,
+                        logical_key: Key::Character(key),
+                        ..
+                    },
+                ..
+            } if key.as_str() == "0"
+                && (self.modifiers.control_key() || self.modifiers.super_key()) =>
+            {
+                self.renderer()
+                    .lock()
+                    .unwrap()
+                    .set_view(ViewTransform::default());
+            }
+            WindowEvent::KeyboardInput {
+                event:
+                    KeyEvent {
+                        state,
+                        logical_key: Key::Named(NamedKey::Space),
+                        ..
+                    },
+                ..
+            } => {
+                self.space_pressed = state == ElementState::Pressed;
+            }
+            WindowEvent::MouseWheel { delta, .. } => {
+                // One notch of a mouse wheel zooms by 10%, touchpads scroll by pixels
+                let factor = match delta {
+                    MouseScrollDelta::LineDelta(_, lines) => 1.1f32.powf(lines),
+                    MouseScrollDelta::PixelDelta(pixels) => 1.002f32.powf(pixels.y as f32),
+                };
+                self.zoom_at_cursor(factor);
+                if self.shape_start.is_some() {
+                    self.update_shape_preview();
+                }
+            }
 
             WindowEvent::CursorMoved { position, .. } => {
-                self.mouse_position = (position.x as f32, position.y as f32);
+                self.pressure = self.mouse_pressure();
+                self.pointer_moved((position.x as f32, position.y as f32));
             }
-            WindowEvent::RedrawRequested => {
-                // let window = self.window.as_ref().unwrap();
-                let rendptr = self.renderer();
-                let mut renderer = rendptr.lock().unwrap();
-
-                if self.mouse_pressed {
-                    let mut paint = renderer.begin_render();
-                    rect.draw_to(
-                        &mut renderer,
-                        &mut paint,
-                        [self.mouse_position.0, self.mouse_position.1],
-                    );
-                    renderer.end_render(paint);
+            WindowEvent::ModifiersChanged(modifiers) => {
+                self.modifiers = modifiers.state();
+                if self.shape_start.is_some() {
+                    self.update_shape_preview();
                 }
-
-                match renderer.render() {
+            }
+            WindowEvent::RedrawRequested => {
+                // The state must not be borrowed anymore if it has to be replaced
+                let result = self.renderer().lock().unwrap().render();
+                match result {
                     Ok(_) => {}
-                    // Reconfigure the surface if lost
-                    Err(wgpu::SurfaceError::Lost) => {}
-                    // The system is out of memory, we should probably quit
-                    Err(wgpu::SurfaceError::OutOfMemory) => unimplemented!(),
-                    // All other errors (Outdated, Timeout) should be resolved by the next frame
-                    Err(e) => eprintln!("{:?}", e),
+                    Err(wgpu::SurfaceError::Lost | wgpu::SurfaceError::Outdated) => {
+                        self.renderer().lock().unwrap().reconfigure_surface();
+                        self.window.as_ref().unwrap().request_redraw();
+                    }
+                    // Start over on a new device rather than give up on the doodle
+                    Err(wgpu::SurfaceError::OutOfMemory) => {
+                        log::error!("Out of memory while presenting");
+                        self.recreate_state();
+                    }
+                    // A timeout should be resolved by the next frame
+                    Err(e) => {
+                        log::warn!("{:?}", e);
+                        self.window.as_ref().unwrap().request_redraw();
+                    }
                 }
             }
             WindowEvent::Resized(new_size) => {
-                println!("Resized to {:?}", new_size);
-                if let Some(state) = self.state.as_ref() {
-                    if new_size.width > 0 && new_size.height > 0 {
-                        let mut state_lock = state.lock().unwrap();
-                        state_lock.config.width = new_size.width;
-                        state_lock.config.height = new_size.height;
-                        state_lock
-                            .surface
-                            .configure(&state_lock.device, &state_lock.config);
-                    }
-                }
+                info!("Resized to {:?}", new_size);
+                self.renderer().lock().unwrap().resize(new_size);
+            }
+            WindowEvent::ScaleFactorChanged { scale_factor, .. } => {
+                // The physical size of the window changes with the scale factor
+                info!("Scale factor changed to {}", scale_factor);
+                let new_size = self.window.as_ref().unwrap().inner_size();
+                self.renderer().lock().unwrap().resize(new_size);
             }
             _ => {}
         }
@@ -233,15 +919,81 @@ impl ApplicationHandler<Events> for CanvasApp {
     fn user_event(&mut self, _event_loop: &winit::event_loop::ActiveEventLoop, event: Events) {
         info!("User event: {:?}", event);
         match event {
-            Events::Close => {}
+            Events::Close => self.close(),
+            Events::Layer(command) => {
+                if let Some(state) = self.state.as_ref() {
+                    let changed = {
+                        let mut state = state.lock().unwrap();
+                        let (count, active) = (state.layer_count(), state.active_layer());
+                        // The recorded strokes follow their layer, or go away with it
+                        self.strokes.retain_mut(|stroke| {
+                            match command.remap_index(stroke.layer, count, active) {
+                                Some(layer) => {
+                                    stroke.layer = layer;
+                                    true
+                                }
+                                None => false,
+                            }
+                        });
+                        state.apply_layer_command(command)
+                    };
+                    if changed {
+                        edit_finished(&self.snapshot, &self.callbacks);
+                        self.update_document();
+                    }
+                }
+            }
+            Events::Undo => {
+                if self.state.is_some() {
+                    self.undo();
+                }
+            }
+            Events::FitToWindow => {
+                if let Some(state) = self.state.as_ref() {
+                    state.lock().unwrap().set_view(ViewTransform::default());
+                }
+            }
+            Events::Tool(command) => {
+                let tool = self.tool_settings.tool;
+                self.tool_settings.apply(command);
+                if self.tool_settings.tool != tool {
+                    callbacks::call(&self.callbacks.tool_changed, self.tool_settings.tool);
+                }
+                if self.tool_settings.tool != Tool::Brush {
+                    self.finish_stroke();
+                }
+                if let Some(state) = self.state.as_ref() {
+                    let mut state = state.lock().unwrap();
+                    // Switching tools in the middle of a drag drops the shape
+                    if self.tool_settings.tool.shape_kind().is_none() {
+                        state.set_shape_preview(None);
+                        self.shape_start = None;
+                    }
+                }
+            }
+            Events::LoadImage(import) => {
+                if self.state.is_some() {
+                    self.load_image(&import, true);
+                } else {
+                    self.initial_image = Some(import);
+                }
+            }
+            Events::Background(background) => {
+                self.background = background;
+                if let Some(state) = self.state.as_ref() {
+                    state.lock().unwrap().set_background(background);
+                    edit_finished(&self.snapshot, &self.callbacks);
+                    self.update_document();
+                }
+            }
             Events::NewState(state) => {
+                state.lock().unwrap().set_background(self.background);
                 self.state = Some(state);
-                let rendptr = self.renderer();
-                let mut renderer = rendptr.lock().unwrap();
-                let mut clear_screen = renderer.begin_render();
-                renderer.clear_screen(&mut clear_screen);
-                renderer.end_render(clear_screen);
-                let _ = renderer.render();
+                if let Some(import) = self.initial_image.take() {
+                    self.load_image(&import, false);
+                }
+                self.update_document();
+                let _ = self.renderer().lock().unwrap().render();
             }
         }
     }
@@ -254,8 +1006,4 @@ impl ApplicationHandler<Events> for CanvasApp {
     ) {
         let _ = (event_loop, device_id, event);
     }
-    fn about_to_wait(&mut self, _event_loop: &ActiveEventLoop) {
-        let window = self.window.as_ref().unwrap();
-        window.request_redraw();
-    }
 }
@@ -0,0 +1,425 @@
+// Golden image tests for the headless renderer.
+// Run with UPDATE_GOLDEN=1 to regenerate the images in tests/golden after an intended rendering change.
+use std::path::PathBuf;
+
+use image::{Rgba, RgbaImage};
+use winit::dpi::PhysicalSize;
+use DoodlingCanvas::{
+    color,
+    cpu_raster::CpuCanvas,
+    document::{LayerSettings, StrokeData, StrokeDocument},
+    layers::{Background, BlendMode, LayerCommand},
+    render_state::{State, UndoKind},
+    shapes::{Shape, ShapeKind},
+    stroke::{Dab, PressureCurve, Stroke, StrokePoint},
+    viewport::ViewTransform,
+};
+
+// Largest per channel difference still accepted, drivers are allowed to round differently
+const TOLERANCE: u8 = 3;
+
+// Tests are skipped rather than failed on machines without any adapter, software ones included
+fn headless_state(width: u32, height: u32) -> Option<State> {
+    let _ = env_logger::builder().is_test(true).try_init();
+    match pollster::block_on(State::new_headless(PhysicalSize::new(width, height))) {
+        Ok(state) => Some(state),
+        Err(err) => {
+            eprintln!("Skipping headless test: {}", err);
+            None
+        }
+    }
+}
+
+fn assert_matches_golden(name: &str, image: &RgbaImage) {
+    let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/golden")
+        .join(format!("{}.png", name));
+    if std::env::var_os("UPDATE_GOLDEN").is_some() {
+        image.save(&path).expect("Failed to write golden image");
+        return;
+    }
+    let golden = image::open(&path)
+        .unwrap_or_else(|err| panic!("Failed to open {}: {}", path.display(), err))
+        .to_rgba8();
+    assert_eq!(image.dimensions(), golden.dimensions());
+    let mismatches = image
+        .pixels()
+        .zip(golden.pixels())
+        .filter(|(actual, expected)| {
+            actual
+                .0
+                .iter()
+                .zip(expected.0.iter())
+                .any(|(a, b)| a.abs_diff(*b) > TOLERANCE)
+        })
+        .count();
+    if mismatches > 0 {
+        let actual_path = path.with_extension("actual.png");
+        let _ = image.save(&actual_path);
+        panic!(
+            "{} pixels differ from {}, the rendered image was saved to {}",
+            mismatches,
+            path.display(),
+            actual_path.display()
+        );
+    }
+}
+
+fn stroke(layer: usize, color: Rgba<u8>, size: f32, points: &[(f32, f32, f32)]) -> Stroke {
+    let mut stroke = Stroke::new(layer, color, size, PressureCurve::default());
+    for &(x, y, pressure) in points {
+        stroke.push(StrokePoint { x, y, pressure });
+    }
+    stroke
+}
+
+#[test]
+fn blank_canvas_is_the_background_color() {
+    let Some(mut state) = headless_state(32, 16) else {
+        return;
+    };
+    let image = pollster::block_on(state.extract_framebuffer()).unwrap();
+    assert_eq!(image.dimensions(), (32, 16));
+    assert!(image
+        .pixels()
+        .all(|pixel| *pixel == Rgba([124, 124, 124, 255])));
+}
+
+#[test]
+fn only_changes_mark_the_canvas_dirty() {
+    let Some(mut state) = headless_state(32, 32) else {
+        return;
+    };
+    assert!(state.is_dirty());
+    state.render().unwrap();
+    assert!(!state.is_dirty());
+    // Reading the canvas back or snapshotting it for undo leaves the screen as it is
+    pollster::block_on(state.extract_framebuffer()).unwrap();
+    state.push_undo(0, UndoKind::Paint);
+    assert!(!state.is_dirty());
+    state.draw_stroke(&stroke(0, Rgba([255, 0, 0, 255]), 4.0, &[(8.0, 8.0, 1.0)]));
+    assert!(state.is_dirty());
+    state.render().unwrap();
+    state.set_view(ViewTransform::default());
+    assert!(state.is_dirty());
+}
+
+#[test]
+fn brush_strokes() {
+    let Some(mut state) = headless_state(128, 96) else {
+        return;
+    };
+    // Pressure ramps up along the first stroke, so it gets thicker and more opaque
+    state.draw_stroke(&stroke(
+        0,
+        Rgba([220, 30, 30, 255]),
+        12.0,
+        &[(10.0, 80.0, 0.1), (60.0, 20.0, 0.6), (118.0, 70.0, 1.0)],
+    ));
+    state.draw_stroke(&stroke(
+        0,
+        Rgba([30, 60, 220, 128]),
+        6.0,
+        &[(10.0, 10.0, 1.0), (118.0, 90.0, 1.0)],
+    ));
+    let image = pollster::block_on(state.extract_framebuffer()).unwrap();
+    assert_matches_golden("brush_strokes", &image);
+}
+
+#[test]
+fn shapes() {
+    let Some(mut state) = headless_state(128, 96) else {
+        return;
+    };
+    let shapes = [
+        Shape {
+            kind: ShapeKind::Rectangle,
+            start: [8.0, 8.0],
+            end: [70.0, 50.0],
+            filled: false,
+            thickness: 4.0,
+            color: Rgba([0, 0, 0, 255]),
+        },
+        Shape {
+            kind: ShapeKind::Ellipse,
+            start: [40.0, 30.0],
+            end: [120.0, 90.0],
+            filled: true,
+            thickness: 1.0,
+            color: Rgba([40, 200, 90, 160]),
+        },
+        Shape {
+            kind: ShapeKind::Line,
+            start: [4.0, 92.0],
+            end: [124.0, 4.0],
+            filled: false,
+            thickness: 3.0,
+            color: Rgba([250, 220, 20, 255]),
+        },
+    ];
+    // The shape uniform is written through the queue, so each shape is submitted on its own like the app does
+    for shape in &shapes {
+        let mut commands = state.begin_render();
+        state.draw_shape(&mut commands, shape);
+        state.end_render(commands);
+    }
+    let image = pollster::block_on(state.extract_framebuffer()).unwrap();
+    assert_matches_golden("shapes", &image);
+}
+
+#[test]
+fn layer_opacity_and_blend_modes() {
+    let Some(mut state) = headless_state(96, 96) else {
+        return;
+    };
+    state.draw_stroke(&stroke(
+        0,
+        Rgba([240, 200, 40, 255]),
+        30.0,
+        &[(10.0, 48.0, 1.0), (86.0, 48.0, 1.0)],
+    ));
+    state.apply_layer_command(LayerCommand::Add);
+    state.apply_layer_command(LayerCommand::SetBlendMode(1, BlendMode::Multiply));
+    state.draw_stroke(&stroke(
+        1,
+        Rgba([40, 120, 240, 255]),
+        30.0,
+        &[(48.0, 10.0, 1.0), (48.0, 86.0, 1.0)],
+    ));
+    state.apply_layer_command(LayerCommand::Add);
+    state.apply_layer_command(LayerCommand::SetOpacity(2, 0.5));
+    state.draw_stroke(&stroke(
+        2,
+        Rgba([255, 255, 255, 255]),
+        16.0,
+        &[(10.0, 10.0, 1.0), (86.0, 86.0, 1.0)],
+    ));
+    let image = pollster::block_on(state.extract_framebuffer()).unwrap();
+    assert_matches_golden("layers", &image);
+}
+
+#[test]
+fn layer_commands_report_changes() {
+    let Some(mut state) = headless_state(16, 16) else {
+        return;
+    };
+    assert!(state.apply_layer_command(LayerCommand::Add));
+    // Selecting, invalid indices and settings that are already set leave the canvas as it is
+    assert!(!state.apply_layer_command(LayerCommand::Select(0)));
+    assert!(!state.apply_layer_command(LayerCommand::Remove(5)));
+    assert!(!state.apply_layer_command(LayerCommand::Move { from: 1, to: 1 }));
+    assert!(!state.apply_layer_command(LayerCommand::SetVisible(1, true)));
+    assert!(!state.apply_layer_command(LayerCommand::SetOpacity(1, 1.0)));
+    assert!(state.apply_layer_command(LayerCommand::SetOpacity(1, 0.5)));
+    assert!(state.apply_layer_command(LayerCommand::SetBlendMode(1, BlendMode::Screen)));
+    assert!(state.apply_layer_command(LayerCommand::Remove(1)));
+    assert!(!state.apply_layer_command(LayerCommand::Remove(0)));
+}
+
+#[test]
+fn undo_restores_the_layer_and_reports_the_step() {
+    let Some(mut state) = headless_state(16, 16) else {
+        return;
+    };
+    state.push_undo(0, UndoKind::Paint);
+    state.push_undo(0, UndoKind::Stroke);
+    state.draw_stroke(&stroke(0, Rgba([255, 0, 0, 255]), 8.0, &[(8.0, 8.0, 1.0)]));
+    assert_eq!(state.undo(), Some(UndoKind::Stroke));
+    let image = pollster::block_on(state.extract_framebuffer()).unwrap();
+    assert!(image
+        .pixels()
+        .all(|pixel| *pixel == Rgba([124, 124, 124, 255])));
+    assert_eq!(state.undo(), Some(UndoKind::Paint));
+    assert_eq!(state.undo(), None);
+}
+
+// Pixels whose center is right on a dab's edge can go either way depending on the driver's precision
+fn assert_matches_cpu(gpu: &RgbaImage, cpu: &RgbaImage) {
+    let differences: Vec<_> = gpu
+        .enumerate_pixels()
+        .zip(cpu.pixels())
+        .filter(|((_, _, gpu), cpu)| {
+            gpu.0
+                .iter()
+                .zip(cpu.0.iter())
+                .any(|(a, b)| a.abs_diff(*b) > TOLERANCE)
+        })
+        .map(|((x, y, gpu), cpu)| (x, y, *gpu, *cpu))
+        .collect();
+    assert!(
+        differences.len() <= (gpu.width() * gpu.height()) as usize / 1000,
+        "{} pixels differ, first ones: {:?}",
+        differences.len(),
+        &differences[..differences.len().min(5)]
+    );
+}
+
+// The CPU rasterizer is the reference for the GPU pipeline, they should agree on every doodle
+#[test]
+fn gpu_matches_cpu_rasterizer() {
+    let json = std::fs::read_to_string(
+        PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/sample_doodle.json"),
+    )
+    .unwrap();
+    let mut document = StrokeDocument::from_json(&json).unwrap();
+    document.layers.push(LayerSettings {
+        opacity: 0.6,
+        visible: true,
+        blend_mode: BlendMode::Screen,
+    });
+    document.strokes.push(StrokeData {
+        layer: 2,
+        color: "#20c040c0".to_owned(),
+        size: 9.0,
+        pressure_curve: 2.0,
+        points: vec![[5.0, 5.0, 0.3], [150.0, 60.0, 1.0], [60.0, 140.0, 0.6]],
+    });
+    let Some(mut state) = headless_state(document.width, document.height) else {
+        return;
+    };
+    document.draw(&mut state).unwrap();
+    let gpu = pollster::block_on(state.extract_framebuffer()).unwrap();
+    let cpu = document.rasterize().unwrap();
+    assert_matches_cpu(&gpu, &cpu);
+}
+
+// Every dab of the batch is drawn by one instanced draw call, whatever its size, rotation and color
+#[test]
+fn batched_rotated_dabs_match_cpu_rasterizer() {
+    let Some(mut state) = headless_state(128, 96) else {
+        return;
+    };
+    let mut cpu = CpuCanvas::new(128, 96);
+    for layer in 0..2 {
+        if layer > 0 {
+            state.apply_layer_command(LayerCommand::Add);
+            cpu.add_layer(1.0, true, BlendMode::Normal);
+        }
+        for i in 0..300 {
+            let dab = Dab {
+                position: [(i * 37 % 128) as f32, (i * 53 % 96) as f32 + 0.25],
+                size: 3.0 + (i % 17) as f32,
+                rotation: i as f32 * 0.1,
+                color: Rgba([
+                    (i * 7 % 256) as u8,
+                    255 - layer as u8 * 100,
+                    90,
+                    120 + (i % 130) as u8,
+                ]),
+            };
+            state.draw_dab(&dab);
+            cpu.draw_dab(layer, &dab);
+        }
+    }
+    let gpu = pollster::block_on(state.extract_framebuffer()).unwrap();
+    assert_matches_cpu(&gpu, &cpu.export());
+}
+
+// Opaque colors come back exactly as they were given, in RGBA order, whichever way they get into a layer
+#[test]
+fn known_colors_round_trip_exactly() {
+    let Some(mut state) = headless_state(256, 4) else {
+        return;
+    };
+    let image = RgbaImage::from_fn(256, 4, |x, y| {
+        let x = x as u8;
+        match y {
+            0 => Rgba([x, 0, 0, 255]),
+            1 => Rgba([0, x, 0, 255]),
+            2 => Rgba([0, 0, x, 255]),
+            _ => Rgba([x, 255 - x, x / 3, 255]),
+        }
+    });
+    state.write_layer(0, &image);
+    assert_eq!(
+        pollster::block_on(state.extract_framebuffer()).unwrap(),
+        image
+    );
+
+    let color = Rgba([255, 128, 7, 255]);
+    state.draw_dab(&Dab {
+        position: [128.0, 2.0],
+        size: 2.0,
+        rotation: 0.0,
+        color,
+    });
+    let exported = pollster::block_on(state.extract_framebuffer()).unwrap();
+    assert_eq!(*exported.get_pixel(128, 2), color);
+    let png = color::encode_png(&exported).unwrap();
+    assert_eq!(image::load_from_memory(&png).unwrap().to_rgba8(), exported);
+}
+
+// The background isn't baked into the export when it's transparent, translucent strokes keep their alpha
+#[test]
+fn transparent_background_exports_alpha() {
+    let Some(mut state) = headless_state(32, 32) else {
+        return;
+    };
+    state.set_background(Background::Transparent);
+    let mut cpu = CpuCanvas::new(32, 32);
+    cpu.set_background(Background::Transparent);
+    let dab = Dab {
+        position: [16.0, 16.0],
+        size: 12.0,
+        rotation: 0.0,
+        color: Rgba([40, 120, 220, 128]),
+    };
+    state.draw_dab(&dab);
+    cpu.draw_dab(0, &dab);
+    let gpu = pollster::block_on(state.extract_framebuffer()).unwrap();
+    assert_eq!(*gpu.get_pixel(0, 0), Rgba([0, 0, 0, 0]));
+    assert_eq!(gpu.get_pixel(16, 16).0[3], 128);
+    assert_matches_cpu(&gpu, &cpu.export());
+}
+
+// What the app does after losing the device: snapshot the canvas, build a new state and restore it there
+#[test]
+fn snapshot_restores_into_a_new_state() {
+    let Some(mut state) = headless_state(64, 48) else {
+        return;
+    };
+    state.draw_stroke(&stroke(
+        0,
+        Rgba([200, 30, 30, 255]),
+        6.0,
+        &[(4.0, 4.0, 1.0), (60.0, 40.0, 1.0)],
+    ));
+    state.apply_layer_command(LayerCommand::Add);
+    state.apply_layer_command(LayerCommand::SetBlendMode(1, BlendMode::Multiply));
+    state.apply_layer_command(LayerCommand::SetOpacity(1, 0.5));
+    state.draw_stroke(&stroke(
+        1,
+        Rgba([30, 30, 200, 160]),
+        10.0,
+        &[(60.0, 4.0, 1.0), (4.0, 40.0, 0.5)],
+    ));
+    state.set_view(ViewTransform {
+        zoom: 2.0,
+        pan: (3.0, -4.0),
+    });
+    state.set_background(Background::Transparent);
+    let snapshot = pollster::block_on(state.snapshot()).unwrap();
+    let expected = pollster::block_on(state.extract_framebuffer()).unwrap();
+
+    let mut restored = headless_state(64, 48).unwrap();
+    restored.restore(&snapshot);
+    assert_eq!(restored.layer_count(), 2);
+    assert_eq!(restored.active_layer(), 1);
+    assert_eq!(restored.view(), state.view());
+    assert_eq!(restored.background(), Background::Transparent);
+    assert_eq!(
+        pollster::block_on(restored.extract_framebuffer()).unwrap(),
+        expected
+    );
+}
+
+#[test]
+fn destroying_the_device_marks_it_lost() {
+    let Some(state) = headless_state(16, 16) else {
+        return;
+    };
+    assert!(!state.is_device_lost());
+    state.device.destroy();
+    state.device.poll(wgpu::Maintain::Wait);
+    assert!(state.is_device_lost());
+}
@@ -1,14 +1,28 @@
 pub(crate) use async_trait::async_trait;
-use surrealdb::{Surreal, engine::remote::ws::Client};
+use surrealdb::{Surreal, engine::remote::ws::Client, sql::{Id, Thing}};
 use anyhow::Result;
 
-use crate::model::DoodleEntry;
+use crate::model::{DoodleEntry, DoodleLink, RemixLink};
+
+// Remixes point to the doodle they were made from: Doodles:remix->remix_of->Doodles:parent
+const REMIXED_FROM: &str = "->remix_of->Doodles";
+const REMIXES: &str = "<-remix_of<-Doodles";
 
 #[async_trait]
 pub trait DoodleDataStore : Clone + Send + Sync
 {
     async fn get_recent_doodles(&self,limit: usize) -> Result<Vec<DoodleEntry>>;
-    async fn create_doodle(&self, doodle: DoodleEntry) -> Result<()>;
+    async fn get_doodle(&self, id: &str) -> Result<Option<DoodleEntry>>;
+    // The stroke document of the doodle, None when it has none or doesn't exist
+    async fn get_doodle_strokes(&self, id: &str) -> Result<Option<String>>;
+    // Returns the id of the new doodle
+    async fn create_doodle(&self, doodle: DoodleEntry, parent_id: Option<String>) -> Result<String>;
+    async fn get_remixed_from(&self, id: &str) -> Result<Vec<DoodleLink>>;
+    async fn get_remixes(&self, id: &str) -> Result<Vec<DoodleLink>>;
+    // The doodle followed by the doodles it was remixed from, nearest first, at most `depth` of those
+    async fn get_ancestors(&self, id: &str, depth: usize) -> Result<Vec<DoodleLink>>;
+    // The remixes of all the given doodles, at most `limit` of them
+    async fn get_remixes_of(&self, ids: &[String], limit: usize) -> Result<Vec<RemixLink>>;
 }
 
 #[derive(Clone)]
@@ -24,6 +38,18 @@ impl SurrealDoodleConnection
             surreal_client: client
         }
     }
+
+    // Doodles one step away from this one along the graph path
+    async fn get_related(&self, id: &str, path: &str) -> Result<Vec<DoodleLink>>
+    {
+        let query = format!("SELECT meta::id(id) AS id, name FROM array::flatten((SELECT VALUE {} FROM type::thing('Doodles', $id)))", path);
+        Ok(self.surreal_client
+        .query(query)
+        .bind(("id",id))
+        .await?
+        .take(0)?
+        )
+    }
 }
 
 #[async_trait]
@@ -32,16 +58,96 @@ impl DoodleDataStore for SurrealDoodleConnection
     async fn get_recent_doodles(&self,limit: usize) -> Result<Vec<DoodleEntry>>
     {
         Ok(self.surreal_client
-        .query("SELECT * FROM Doodles LIMIT $limit")
+        .query("SELECT meta::id(id) AS id, name, description, data FROM Doodles LIMIT $limit")
         .bind(("limit",limit))
         .await?
         .take(0)?
         )
     }
 
-    async fn create_doodle(&self, doodle: DoodleEntry) -> Result<()>
+    async fn get_doodle(&self, id: &str) -> Result<Option<DoodleEntry>>
+    {
+        Ok(self.surreal_client
+        .query("SELECT meta::id(id) AS id, name, description, data FROM type::thing('Doodles', $id)")
+        .bind(("id",id))
+        .await?
+        .take(0)?
+        )
+    }
+
+    async fn get_doodle_strokes(&self, id: &str) -> Result<Option<String>>
     {
-        self.surreal_client.create::<Vec<DoodleEntry>>("Doodles").content::<DoodleEntry>(doodle).await?;
-        Ok(())
+        let strokes : Vec<Option<String>> = self.surreal_client
+        .query("SELECT VALUE strokes FROM type::thing('Doodles', $id)")
+        .bind(("id",id))
+        .await?
+        .take(0)?;
+        Ok(strokes.into_iter().next().flatten())
+    }
+
+    async fn create_doodle(&self, doodle: DoodleEntry, parent_id: Option<String>) -> Result<String>
+    {
+        // The id is picked here so the remix can be related to its parent in the same transaction,
+        // a failed RELATE must not leave the doodle behind without its parent
+        let created = Thing { tb: "Doodles".to_owned(), id: Id::rand() };
+        let relate = if parent_id.is_some() { "RELATE $created->remix_of->$parent;" } else { "" };
+        let mut query = self.surreal_client
+        .query(format!("BEGIN TRANSACTION; CREATE ONLY $created CONTENT $doodle; {} COMMIT TRANSACTION;", relate))
+        .bind(("created",created.clone()))
+        .bind(("doodle",doodle));
+        if let Some(parent_id) = parent_id
+        {
+            query = query.bind(("parent",Thing::from(("Doodles",parent_id.as_str()))));
+        }
+        query.await?.check()?;
+        Ok(created.id.to_raw())
+    }
+
+    async fn get_remixed_from(&self, id: &str) -> Result<Vec<DoodleLink>>
+    {
+        self.get_related(id, REMIXED_FROM).await
+    }
+
+    async fn get_remixes(&self, id: &str) -> Result<Vec<DoodleLink>>
+    {
+        self.get_related(id, REMIXES).await
+    }
+
+    async fn get_ancestors(&self, id: &str, depth: usize) -> Result<Vec<DoodleLink>>
+    {
+        let doodle : Option<DoodleLink> = self.surreal_client
+        .query("SELECT meta::id(id) AS id, name FROM type::thing('Doodles', $id)")
+        .bind(("id",id))
+        .await?
+        .take(0)?;
+        let mut ancestors : Vec<DoodleLink> = doodle.into_iter().collect();
+        // One hop per query from the doodle found last, so every query stays the same size
+        for _ in 0..depth
+        {
+            let Some(last) = ancestors.last() else
+            {
+                break;
+            };
+            // A remix has a single parent
+            let parent = self.get_remixed_from(&last.id).await?.into_iter().next();
+            match parent
+            {
+                Some(parent) => ancestors.push(parent),
+                None => break,
+            }
+        }
+        Ok(ancestors)
+    }
+
+    async fn get_remixes_of(&self, ids: &[String], limit: usize) -> Result<Vec<RemixLink>>
+    {
+        let ids : Vec<Thing> = ids.iter().map(|id| Thing::from(("Doodles",id.as_str()))).collect();
+        Ok(self.surreal_client
+        .query("SELECT meta::id(in) AS id, in.name AS name, meta::id(out) AS parent FROM array::flatten((SELECT VALUE <-remix_of FROM $ids)) LIMIT $limit")
+        .bind(("ids",ids))
+        .bind(("limit",limit))
+        .await?
+        .take(0)?
+        )
     }
 }
\ No newline at end of file
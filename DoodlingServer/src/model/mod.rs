@@ -1,10 +1,86 @@
+use anyhow::{anyhow, Result};
+use base64::{engine::general_purpose::STANDARD, Engine};
 use serde::{Deserialize, Serialize};
+use DoodlingCanvas::document::StrokeDocument;
 
+// Allowed range for both the width and the height of a doodle, the limits of the canvas widget
+pub use DoodlingCanvas::utils::{MAX_CANVAS_SIZE as MAX_DOODLE_SIZE, MIN_CANVAS_SIZE as MIN_DOODLE_SIZE};
 
 #[derive(Serialize, Deserialize, Debug,Clone)]
 pub struct DoodleEntry
 {
+    // Key of the record in the Doodles table, set by the datastore and never stored
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
     pub name: String,
     pub description: String,
-    pub data: String
-}
\ No newline at end of file
+    pub data: String,
+    // Stroke document (JSON) the doodle was drawn with, only selected when it's needed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub strokes: Option<String>
+}
+
+// What the create page submits, the parent is set when the doodle is a remix
+#[derive(Deserialize, Debug)]
+pub struct NewDoodle
+{
+    pub name: String,
+    pub description: String,
+    pub data: String,
+    #[serde(default)]
+    pub parent_id: Option<String>,
+    #[serde(default)]
+    pub strokes: Option<String>
+}
+
+// Another doodle of the lineage, without the image
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DoodleLink
+{
+    pub id: String,
+    pub name: String
+}
+
+// One remix_of relation: the remix and the id of the doodle it was made from
+#[derive(Deserialize, Debug)]
+pub struct RemixLink
+{
+    pub id: String,
+    pub name: String,
+    pub parent: String
+}
+
+// A doodle and, recursively, its remixes
+#[derive(Serialize, Debug)]
+pub struct LineageNode
+{
+    pub doodle: DoodleLink,
+    pub remixes: Vec<LineageNode>
+}
+
+impl DoodleEntry
+{
+    // Checks that the data is a base64 encoded PNG with a supported size
+    pub fn validate(&self) -> Result<()>
+    {
+        let bytes = STANDARD.decode(&self.data)?;
+        let (width, height) = image::io::Reader::with_format(std::io::Cursor::new(bytes), image::ImageFormat::Png)
+            .into_dimensions()?;
+        let allowed = MIN_DOODLE_SIZE..=MAX_DOODLE_SIZE;
+        if !allowed.contains(&width) || !allowed.contains(&height)
+        {
+            return Err(anyhow!("Doodle size {}x{} is outside of {}..={}", width, height, MIN_DOODLE_SIZE, MAX_DOODLE_SIZE));
+        }
+        if let Some(strokes) = &self.strokes
+        {
+            StrokeDocument::from_json(strokes)?;
+        }
+        Ok(())
+    }
+
+    // The doodle as PNG bytes
+    pub fn png(&self) -> Result<Vec<u8>>
+    {
+        Ok(STANDARD.decode(&self.data)?)
+    }
+}
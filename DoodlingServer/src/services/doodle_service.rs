@@ -1,9 +1,10 @@
-use axum::{Router, routing::{get, post}, Extension, http::{StatusCode, HeaderMap, header}, response::IntoResponse, Json};
-use crate::{model::DoodleEntry, include_template};
-use anyhow::{Result, Error};
-use minijinja::render;
+use axum::{Router, routing::{get, post}, Extension, extract::Path, http::{StatusCode, HeaderMap, header}, response::IntoResponse, Json};
+use crate::{model::{DoodleEntry, DoodleLink, LineageNode, NewDoodle}, render_template};
+use std::collections::{HashMap, HashSet};
+use anyhow::{anyhow, Result, Error};
 use log::{trace,error};
 use crate::middleware::database_layer::DoodleDataStore;
+use DoodlingCanvas::{document::StrokeDocument, svg};
 
 async fn recent_doodles<DataStore : DoodleDataStore>(db : Extension<DataStore>) -> impl IntoResponse
 {
@@ -11,20 +12,193 @@ async fn recent_doodles<DataStore : DoodleDataStore>(db : Extension<DataStore>)
     let doodles : Vec<DoodleEntry> = db.get_recent_doodles(10).await.unwrap();
     //TODO: Add error-handling instead of expect
 
-    let resp = render!(include_template!{"doodle_list"}, doodles);
+    let resp = render_template!("doodle_list", doodles);
 
     ([(header::CONTENT_TYPE,"text/html")],resp)
 }
-async fn create_doodle<DataStore : DoodleDataStore>(db : Extension<DataStore>,Json(payload): Json<DoodleEntry>) -> impl IntoResponse
+// How far up and down from a doodle its lineage is followed
+const MAX_LINEAGE_DEPTH: usize = 16;
+// Most doodles shown in a lineage tree, it's loaded with every detail page
+const MAX_LINEAGE_NODES: usize = 200;
+
+async fn doodle_detail<DataStore : DoodleDataStore>(db : Extension<DataStore>,Path(id): Path<String>) -> impl IntoResponse
+{
+    trace!("Serving doodle {}",id);
+    let doodle = match db.get_doodle(&id).await
+    {
+        Ok(Some(doodle)) => doodle,
+        Ok(None) => return StatusCode::NOT_FOUND.into_response(),
+        Err(err) =>
+        {
+            error!("Failed to get doodle {}: {:?}",id,err);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+    let related = tokio::try_join!(db.get_remixed_from(&id),db.get_remixes(&id));
+    let (remixed_from, remixes) = match related
+    {
+        Ok(related) => related,
+        Err(err) =>
+        {
+            error!("Failed to get the remixes of doodle {}: {:?}",id,err);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+    let resp = render_template!("doodle_detail", doodle, remixed_from, remixes);
+    ([(header::CONTENT_TYPE,"text/html")],resp).into_response()
+}
+// Walks up to the first doodle of the lineage, then down through its remixes one level at a time.
+// Every step is a single query, and the tree stops growing at MAX_LINEAGE_NODES doodles
+async fn lineage<DataStore : DoodleDataStore>(db : &DataStore, id: &str) -> Result<LineageNode>
+{
+    let ancestors = db.get_ancestors(id, MAX_LINEAGE_DEPTH).await?;
+    let root = ancestors.last().cloned().ok_or_else(|| anyhow!("No doodle {}",id))?;
+    let mut children : HashMap<String, Vec<DoodleLink>> = HashMap::new();
+    let mut level = vec![root.id.clone()];
+    let mut seen = HashSet::from([root.id.clone()]);
+    for _ in 0..MAX_LINEAGE_DEPTH
+    {
+        let remaining = MAX_LINEAGE_NODES.saturating_sub(seen.len());
+        if level.is_empty() || remaining == 0
+        {
+            break;
+        }
+        let mut next = Vec::new();
+        for remix in db.get_remixes_of(&level, remaining).await?
+        {
+            if seen.insert(remix.id.clone())
+            {
+                next.push(remix.id.clone());
+                children.entry(remix.parent).or_default().push(DoodleLink { id: remix.id, name: remix.name });
+            }
+        }
+        level = next;
+    }
+    fn build(doodle: DoodleLink, children: &mut HashMap<String, Vec<DoodleLink>>) -> LineageNode
+    {
+        let remixes = children.remove(&doodle.id).unwrap_or_default();
+        LineageNode {
+            remixes: remixes.into_iter().map(|remix| build(remix, children)).collect(),
+            doodle,
+        }
+    }
+    Ok(build(root, &mut children))
+}
+async fn doodle_lineage<DataStore : DoodleDataStore>(db : Extension<DataStore>,Path(id): Path<String>) -> impl IntoResponse
+{
+    trace!("Serving lineage of doodle {}",id);
+    let lineage = match lineage(&*db, &id).await
+    {
+        Ok(lineage) => lineage,
+        Err(err) =>
+        {
+            error!("Failed to get the lineage of doodle {}: {:?}",id,err);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+    let current = id;
+    let resp = render_template!("lineage_tree", lineage, current);
+    ([(header::CONTENT_TYPE,"text/html")],resp).into_response()
+}
+// The doodle as a PNG file, e.g. to load it into the canvas
+async fn doodle_image<DataStore : DoodleDataStore>(db : Extension<DataStore>,Path(id): Path<String>) -> impl IntoResponse
+{
+    trace!("Serving image of doodle {}",id);
+    let png = match db.get_doodle(&id).await
+    {
+        Ok(Some(doodle)) => doodle.png(),
+        Ok(None) => return StatusCode::NOT_FOUND.into_response(),
+        Err(err) =>
+        {
+            error!("Failed to get doodle {}: {:?}",id,err);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+    match png
+    {
+        Ok(png) => ([(header::CONTENT_TYPE,"image/png")],png).into_response(),
+        Err(err) =>
+        {
+            error!("Doodle {} has invalid data: {:?}",id,err);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+// The strokes of the doodle as an SVG document, only for doodles saved with their strokes
+async fn doodle_svg<DataStore : DoodleDataStore>(db : Extension<DataStore>,Path(id): Path<String>) -> impl IntoResponse
+{
+    trace!("Serving SVG of doodle {}",id);
+    let strokes = match db.get_doodle_strokes(&id).await
+    {
+        Ok(Some(strokes)) => strokes,
+        Ok(None) => return StatusCode::NOT_FOUND.into_response(),
+        Err(err) =>
+        {
+            error!("Failed to get the strokes of doodle {}: {:?}",id,err);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+    let document = match StrokeDocument::from_json(&strokes)
+    {
+        Ok(document) => document,
+        Err(err) =>
+        {
+            error!("Doodle {} has invalid strokes: {:?}",id,err);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+    // Fills, shapes and imported images aren't recorded, the strokes alone don't show the doodle
+    if document.incomplete
+    {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+    match svg::to_svg(&document)
+    {
+        Ok(svg) => ([(header::CONTENT_TYPE,"image/svg+xml")],svg).into_response(),
+        Err(err) =>
+        {
+            error!("Doodle {} has invalid strokes: {:?}",id,err);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+async fn create_doodle<DataStore : DoodleDataStore>(db : Extension<DataStore>,Json(payload): Json<NewDoodle>) -> impl IntoResponse
 {
     trace!("Creating doodle: {}",payload.name);
     let doodle = DoodleEntry {
+        id: None,
         name: payload.name,
         description: payload.description,
-        data : payload.data
+        data : payload.data,
+        // The form sends no strokes when the doodle wasn't drawn in the canvas or has more than strokes
+        strokes: payload.strokes.filter(|strokes| !strokes.is_empty())
     };
-    let x : Result<(),Error> = db.create_doodle(doodle).await;
     let mut header = HeaderMap::new();
+    if let Err(err) = doodle.validate()
+    {
+        error!("Rejected doodle: {:?}",err);
+        return (StatusCode::BAD_REQUEST,header);
+    }
+    // The form sends an empty parent for doodles that aren't remixes
+    let parent_id = payload.parent_id.filter(|id| !id.is_empty());
+    if let Some(parent_id) = &parent_id
+    {
+        match db.get_doodle(parent_id).await
+        {
+            Ok(Some(_)) => {}
+            Ok(None) =>
+            {
+                error!("Rejected remix of unknown doodle {}",parent_id);
+                return (StatusCode::BAD_REQUEST,header);
+            }
+            Err(err) =>
+            {
+                error!("Failed to get the parent doodle {}: {:?}",parent_id,err);
+                return (StatusCode::INTERNAL_SERVER_ERROR,header);
+            }
+        }
+    }
+    let x : Result<String,Error> = db.create_doodle(doodle, parent_id).await;
     if !x.is_ok()
     {
         error!("Failed to create doodle: {:?}",x.err());
@@ -38,6 +212,10 @@ pub fn create_doodle_service<DataStore : DoodleDataStore + 'static>(db :DataStor
 {
     Router::new()
         .route("/recent-doodles",get(recent_doodles::<DataStore>))
+        .route("/doodles/:id", get(doodle_detail::<DataStore>))
+        .route("/doodles/:id/image", get(doodle_image::<DataStore>))
+        .route("/doodles/:id/image.svg", get(doodle_svg::<DataStore>))
+        .route("/doodles/:id/lineage", get(doodle_lineage::<DataStore>))
         .route("/create-doodle", post(create_doodle::<DataStore>))
         .layer(Extension(db))
 }
\ No newline at end of file
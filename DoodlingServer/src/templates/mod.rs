@@ -1,7 +1,22 @@
-
 #[macro_export]
 macro_rules! include_template {
     ($name : literal) => {
         std::include_str!{concat!{std::env!{"CARGO_MANIFEST_DIR"},"/DoodlingHtmx/templates/",$name,".html"}}
     };
-}
\ No newline at end of file
+}
+
+// Renders a template like minijinja::render!, but with HTML escaping. render! uses an unnamed template,
+// which isn't escaped, and the names and descriptions put in the templates are submitted by users
+#[macro_export]
+macro_rules! render_template {
+    ($name : literal, $($context : tt)*) => {
+        $crate::templates::render_html($crate::include_template!{$name}, minijinja::context!{$($context)*})
+    };
+}
+
+pub fn render_html(source: &str, context: minijinja::Value) -> String
+{
+    let mut env = minijinja::Environment::new();
+    env.set_auto_escape_callback(|_| minijinja::AutoEscape::Html);
+    env.render_str(source, context).expect("Failed to render template")
+}